@@ -0,0 +1,46 @@
+pub mod token;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::config::ServerConfig;
+
+/// Everything the http routes need, handed to axum as router state
+#[derive(Debug, Clone)]
+pub struct ApiState {
+    pub client: Client,
+    pub discord_api_base: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+impl ApiState {
+    pub fn new(config: &ServerConfig, client: Client) -> Self {
+        ApiState {
+            client,
+            discord_api_base: config.discord_api_base.trim_end_matches('/').to_string(),
+            client_id: config.discord_client_id.clone(),
+            client_secret: config.discord_client_secret.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// An error that is safe to hand back to the activity, along with the status to send it with
+#[derive(Debug)]
+pub struct ApiError(pub StatusCode, pub String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorBody { error: self.1 })).into_response()
+    }
+}
@@ -0,0 +1,104 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::{ApiError, ApiState};
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+}
+
+/// The parts of discord's token response the activity cares about
+#[derive(Debug, Deserialize)]
+struct DiscordToken {
+    access_token: String,
+}
+
+/// Swap the authorization code the Embedded App SDK hands the activity for an access token.
+/// The activity then connects to the socket with it as `aut`.
+pub async fn exchange_token(
+    State(state): State<ApiState>,
+    Json(req): Json<TokenRequest>,
+) -> Result<Json<TokenResponse>, ApiError> {
+    let (Some(client_id), Some(client_secret)) = (&state.client_id, &state.client_secret) else {
+        error!("Token exchange requested but no discord client credentials are configured");
+        return Err(ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "token exchange is not configured".to_string(),
+        ));
+    };
+
+    let res = state
+        .client
+        .post(format!("{}/oauth2/token", state.discord_api_base))
+        .form(&[
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", req.code.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|err| {
+            error!("Token Exchange Error: {}", err);
+            ApiError(
+                StatusCode::BAD_GATEWAY,
+                "unable to reach discord".to_string(),
+            )
+        })?;
+
+    match res.status() {
+        // Discord refused our client credentials, that's on the server and not the activity
+        StatusCode::UNAUTHORIZED => {
+            error!("Token Exchange Error: discord rejected the client credentials");
+            return Err(ApiError(
+                StatusCode::BAD_GATEWAY,
+                "token exchange is misconfigured".to_string(),
+            ));
+        }
+        StatusCode::TOO_MANY_REQUESTS => {
+            error!("Token Exchange Error: rate limited by discord");
+            return Err(ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many token exchanges, try again later".to_string(),
+            ));
+        }
+        status if status.is_client_error() => {
+            warn!("Token Exchange rejected by discord with {}", status);
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                "authorization code was rejected".to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    let token = res
+        .error_for_status()
+        .map_err(|err| {
+            error!("Token Exchange Error: {}", err);
+            ApiError(
+                StatusCode::BAD_GATEWAY,
+                "discord failed to exchange the code".to_string(),
+            )
+        })?
+        .json::<DiscordToken>()
+        .await
+        .map_err(|err| {
+            error!("Token Exchange Error: {}", err);
+            ApiError(
+                StatusCode::BAD_GATEWAY,
+                "unexpected response from discord".to_string(),
+            )
+        })?;
+
+    Ok(Json(TokenResponse {
+        access_token: token.access_token,
+    }))
+}
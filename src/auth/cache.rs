@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+
+use crate::websocket::state::DiscordUser;
+
+use super::{AuthFuture, Authenticator, Rejected};
+
+struct CacheEntry {
+    /// `None` remembers that the token was rejected
+    user: Option<DiscordUser>,
+    expires: Instant,
+}
+
+/// Remembers what another [Authenticator] said about a token for a while, so a wave of
+/// reconnects doesn't turn into a wave of requests to discord. Rejected tokens are remembered
+/// too, for a shorter time. Failures that aren't a rejection, like discord being down, are never
+/// cached.
+pub struct CachedAuthenticator<A> {
+    inner: A,
+    entries: DashMap<String, CacheEntry>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+}
+
+impl<A: Authenticator> CachedAuthenticator<A> {
+    pub fn new(inner: A, ttl: Duration, negative_ttl: Duration, capacity: usize) -> Self {
+        CachedAuthenticator {
+            inner,
+            entries: DashMap::new(),
+            ttl,
+            negative_ttl,
+            capacity,
+        }
+    }
+
+    fn lookup(&self, token: &str) -> Option<Option<DiscordUser>> {
+        let entry = self.entries.get(token)?;
+        if entry.expires <= Instant::now() {
+            drop(entry);
+            self.entries.remove(token);
+            return None;
+        }
+        Some(entry.user.clone())
+    }
+
+    fn store(&self, token: &str, user: Option<DiscordUser>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        let ttl = if user.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+
+        self.entries.insert(
+            token.to_string(),
+            CacheEntry {
+                user,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+
+    /// Drop everything that expired, and if that didn't make room, whatever expires first
+    fn evict(&self) {
+        let now = Instant::now();
+        self.entries.retain(|_, entry| entry.expires > now);
+
+        if self.entries.len() < self.capacity {
+            return;
+        }
+
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|entry| entry.expires)
+            .map(|entry| entry.key().clone());
+        if let Some(token) = oldest {
+            self.entries.remove(&token);
+        }
+    }
+}
+
+impl<A: Authenticator> Authenticator for CachedAuthenticator<A> {
+    fn authenticate<'a>(&'a self, token: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            if let Some(cached) = self.lookup(token) {
+                return cached.ok_or(anyhow!(Rejected));
+            }
+
+            match self.inner.authenticate(token).await {
+                Ok(user) => {
+                    self.store(token, Some(user.clone()));
+                    Ok(user)
+                }
+                Err(err) => {
+                    if err.is::<Rejected>() {
+                        self.store(token, None);
+                    }
+                    Err(err)
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::anyhow;
+use reqwest::{header::AUTHORIZATION, Client, StatusCode};
+
+use crate::websocket::state::DiscordUser;
+
+use super::{AuthFuture, Authenticator, Rejected};
+
+pub const DEFAULT_DISCORD_API_BASE: &str = "https://discord.com/api";
+
+/// Validates tokens against the discord api
+pub struct DiscordAuthenticator {
+    client: Client,
+    api_base: String,
+}
+
+impl DiscordAuthenticator {
+    pub fn new(client: Client, api_base: &str) -> Self {
+        DiscordAuthenticator {
+            client,
+            api_base: api_base.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl Authenticator for DiscordAuthenticator {
+    fn authenticate<'a>(&'a self, token: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let res = self
+                .client
+                .get(format!("{}/users/@me", self.api_base))
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .send()
+                .await?;
+
+            if matches!(
+                res.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) {
+                return Err(anyhow!(Rejected));
+            }
+
+            let user = res.error_for_status()?.json::<DiscordUser>().await?;
+
+            Ok(user)
+        })
+    }
+}
@@ -0,0 +1,94 @@
+pub mod cache;
+pub mod discord;
+pub mod offline;
+
+use std::{fmt, future::Future, ops::Deref, pin::Pin, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Error, Result};
+use reqwest::Client;
+use tracing::warn;
+
+use crate::{config::ServerConfig, websocket::state::DiscordUser};
+
+pub use cache::CachedAuthenticator;
+pub use discord::DiscordAuthenticator;
+pub use offline::{DevAuthenticator, StaticAuthenticator};
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<DiscordUser>> + Send + 'a>>;
+
+/// The token was checked and it's not valid, as opposed to not being able to check it at all
+#[derive(Debug)]
+pub struct Rejected;
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "access token was rejected")
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Turns the access token a client connects with into the user it belongs to
+pub trait Authenticator: Send + Sync {
+    fn authenticate<'a>(&'a self, token: &'a str) -> AuthFuture<'a>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Ask discord who the token belongs to
+    Discord,
+    /// Only accept the tokens from a fixed list, for integration tests
+    Static,
+    /// Accept any token and use it as the user, for local development
+    Dev,
+}
+
+impl FromStr for AuthMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "discord" => Self::Discord,
+            "static" => Self::Static,
+            "dev" => Self::Dev,
+            _ => bail!("unknown auth mode {:?}, expected discord, static or dev", s),
+        })
+    }
+}
+
+/// The authenticator the server was configured with, shared through the socket state
+#[derive(Clone)]
+pub struct Auth(Arc<dyn Authenticator>);
+
+impl Auth {
+    pub fn new(authenticator: impl Authenticator + 'static) -> Self {
+        Auth(Arc::new(authenticator))
+    }
+
+    /// `client` is the shared http client, so connections to discord are pooled
+    pub fn from_config(config: &ServerConfig, client: Client) -> Result<Self> {
+        Ok(match config.auth_mode {
+            AuthMode::Discord => Auth::new(CachedAuthenticator::new(
+                DiscordAuthenticator::new(client, &config.discord_api_base),
+                config.auth_cache_ttl,
+                config.auth_negative_ttl,
+                config.auth_cache_size,
+            )),
+            AuthMode::Static => Auth::new(StaticAuthenticator::parse(
+                config.auth_tokens.as_deref().unwrap_or_default(),
+            )?),
+            AuthMode::Dev => {
+                warn!("Running with dev authentication, every token is accepted");
+                Auth::new(DevAuthenticator)
+            }
+        })
+    }
+}
+
+impl Deref for Auth {
+    type Target = dyn Authenticator;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
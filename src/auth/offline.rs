@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::websocket::state::DiscordUser;
+
+use super::{AuthFuture, Authenticator, Rejected};
+
+/// Accepts a fixed set of tokens, each mapped to a made up user
+pub struct StaticAuthenticator {
+    users: HashMap<String, DiscordUser>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(users: HashMap<String, DiscordUser>) -> Self {
+        StaticAuthenticator { users }
+    }
+
+    /// Read users from a comma separated list of `token:id:username` entries
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let [token, id, username] = entry.splitn(3, ':').collect::<Vec<_>>()[..] else {
+                bail!("auth token entry {:?} is not token:id:username", entry);
+            };
+
+            users.insert(
+                token.to_string(),
+                DiscordUser {
+                    id: id.to_string(),
+                    username: username.to_string(),
+                    global_name: None,
+                    avatar: None,
+                },
+            );
+        }
+
+        Ok(StaticAuthenticator::new(users))
+    }
+}
+
+impl Authenticator for StaticAuthenticator {
+    fn authenticate<'a>(&'a self, token: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move { self.users.get(token).cloned().ok_or(anyhow!(Rejected)) })
+    }
+}
+
+/// Trusts whatever the client sends. The token becomes both the id and name of the user, so two
+/// browser tabs with different tokens are two different players.
+pub struct DevAuthenticator;
+
+impl Authenticator for DevAuthenticator {
+    fn authenticate<'a>(&'a self, token: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            if token.is_empty() {
+                bail!("empty token");
+            }
+
+            Ok(DiscordUser {
+                id: token.to_string(),
+                username: token.to_string(),
+                global_name: None,
+                avatar: None,
+            })
+        })
+    }
+}
@@ -0,0 +1,77 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    auth::{discord::DEFAULT_DISCORD_API_BASE, AuthMode},
+    game::seed::Seed,
+};
+
+/// Server settings, read from the environment at startup
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: String,
+    /// Where party snapshots are written
+    pub snapshot_dir: PathBuf,
+    /// How often every active game is written to disk
+    pub snapshot_interval: Duration,
+    /// How long an empty party and its game are kept around for players to reconnect
+    pub reconnect_grace: Duration,
+    /// How clients are authenticated when they connect
+    pub auth_mode: AuthMode,
+    pub discord_api_base: String,
+    /// `token:id:username` entries for [AuthMode::Static]
+    pub auth_tokens: Option<String>,
+    /// How long a validated token is trusted before asking discord again
+    pub auth_cache_ttl: Duration,
+    /// How long a rejected token is remembered
+    pub auth_negative_ttl: Duration,
+    /// Most tokens kept in the cache at once
+    pub auth_cache_size: usize,
+    /// Timeout for every request the server makes to discord
+    pub http_timeout: Duration,
+    /// OAuth2 application credentials used to exchange the activity's authorization code
+    pub discord_client_id: Option<String>,
+    pub discord_client_secret: Option<String>,
+    /// Mixed into the daily seed so nobody can work out the daily board ahead of time. A random
+    /// one is picked on startup when it isn't set, so the daily board changes with every restart.
+    pub daily_secret: Seed,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(ServerConfig {
+            port: env::var("PORT").unwrap_or("3000".to_string()),
+            snapshot_dir: env::var("SNAPSHOT_DIR")
+                .unwrap_or("snapshots".to_string())
+                .into(),
+            snapshot_interval: Duration::from_secs(parse_var("SNAPSHOT_INTERVAL_SECS", 30)?),
+            reconnect_grace: Duration::from_secs(parse_var("RECONNECT_GRACE_SECS", 60)?),
+            auth_mode: parse_var("AUTH_MODE", AuthMode::Discord)?,
+            discord_api_base: env::var("DISCORD_API_BASE")
+                .unwrap_or(DEFAULT_DISCORD_API_BASE.to_string()),
+            auth_tokens: env::var("AUTH_TOKENS").ok(),
+            auth_cache_ttl: Duration::from_secs(parse_var("AUTH_CACHE_TTL_SECS", 300)?),
+            auth_negative_ttl: Duration::from_secs(parse_var("AUTH_NEGATIVE_TTL_SECS", 30)?),
+            auth_cache_size: parse_var("AUTH_CACHE_SIZE", 10_000)?,
+            http_timeout: Duration::from_secs(parse_var("HTTP_TIMEOUT_SECS", 10)?),
+            discord_client_id: env::var("DISCORD_CLIENT_ID").ok(),
+            discord_client_secret: env::var("DISCORD_CLIENT_SECRET").ok(),
+            daily_secret: parse_var("DAILY_SECRET", Seed::random())?,
+        })
+    }
+}
+
+/// Read an optional variable, falling back to `default` when it isn't set
+fn parse_var<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(Into::into)
+            .with_context(|| format!("invalid value for {}", name)),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::{Area, Point, Size};
+
+/// Smallest board edge a client may ask for
+pub const MIN_BOARD_EDGE: usize = 5;
+/// Largest board edge a client may ask for
+pub const MAX_BOARD_EDGE: usize = 50;
+/// Upper bound on how much of the board may be bones
+pub const MAX_BONE_DENSITY: f32 = 0.25;
+/// Upper bound on bones for boards that must be solvable without guessing. Anything denser takes
+/// too many attempts before the solver manages to clear a layout.
+pub const MAX_NO_GUESS_DENSITY: f32 = 0.21;
+/// Boards with more cells than this have more spots that need a guess, they get a lower limit
+pub const LARGE_BOARD_CELLS: usize = 24 * 24;
+/// Upper bound on bones for large boards that must be solvable without guessing
+pub const MAX_LARGE_NO_GUESS_DENSITY: f32 = 0.18;
+/// Boards with a shorter edge than this leave the solver little room to work around bones
+pub const NARROW_BOARD_EDGE: usize = 16;
+/// Upper bound on bones for narrow boards that must be solvable without guessing
+pub const MAX_NARROW_NO_GUESS_DENSITY: f32 = 0.15;
+/// Boards with a shorter edge than this are barely wider than the safe area around the spawn
+pub const THIN_BOARD_EDGE: usize = 8;
+/// Upper bound on bones for thin boards that must be solvable without guessing
+pub const MAX_THIN_NO_GUESS_DENSITY: f32 = 0.1;
+/// Shortest time a turn may be limited to
+pub const MIN_TURN_TIMEOUT_SECS: u64 = 5;
+/// Longest time a turn may be limited to
+pub const MAX_TURN_TIMEOUT_SECS: u64 = 300;
+/// Shortest time limit a game can have
+pub const MIN_TIME_LIMIT_SECS: u64 = 30;
+/// Longest time limit a game can have
+pub const MAX_TIME_LIMIT_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+/// How many bones to bury, either exactly or as a fraction of the board
+pub enum BoneCount {
+    Count(usize),
+    Density(f32),
+}
+
+impl BoneCount {
+    pub fn resolve(&self, size: Size) -> usize {
+        match self {
+            Self::Count(n) => *n,
+            Self::Density(d) => (size.count() as f32 * d).round() as usize,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Where players start. The area around the spawn never contains bones.
+pub enum Spawn {
+    Fixed(Point),
+    /// Pick any cell on the board, it will be kept safe during generation
+    RandomSafe,
+}
+
+impl Spawn {
+    pub fn resolve<R: Rng>(&self, rng: &mut R, size: Size) -> Point {
+        match self {
+            Self::Fixed(p) => *p,
+            Self::RandomSafe => Area::from(size).point_from_pos(rng.gen_range(0..size.count())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+/// Who gets to see what has been uncovered
+pub enum Visibility {
+    /// The whole party digs on one board and sees every reveal
+    #[default]
+    Shared,
+    /// Competitive mode, every player only sees what they uncovered themselves
+    PerPlayer,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+/// Players take turns instead of everyone acting at once
+pub struct TurnRules {
+    /// Moves, digs and chords a player gets per turn. Marking cells is always free.
+    pub moves: usize,
+    /// How long a player has before their turn is passed on anyway
+    pub timeout_secs: u64,
+}
+
+impl Default for TurnRules {
+    fn default() -> Self {
+        TurnRules {
+            moves: 3,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+/// Optional gameplay rules that can be switched per game
+pub struct Rules {
+    /// Only hand out boards that can be finished without guessing
+    pub no_guess: bool,
+    /// Allow revealing around a number once all its bones are flagged
+    pub chording: bool,
+    /// Shared board for co-op or private views for competitive play
+    pub visibility: Visibility,
+    /// Turn based play, everyone moves freely when this isn't set
+    pub turns: Option<TurnRules>,
+    /// The game is lost once this many seconds have been played
+    pub time_limit_secs: Option<u64>,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            no_guess: true,
+            chording: true,
+            visibility: Visibility::default(),
+            turns: None,
+            time_limit_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+/// Everything needed to generate a new [DigSite](super::digsites::DigSite)
+pub struct GameConfig {
+    pub size: Size,
+    pub bones: BoneCount,
+    pub spawn: Spawn,
+    #[serde(default)]
+    pub rules: Rules,
+}
+
+impl GameConfig {
+    /// Make sure a config is sane and within the limits the server is willing to generate
+    pub fn validate(&self) -> Result<()> {
+        let edges = MIN_BOARD_EDGE..=MAX_BOARD_EDGE;
+        if !edges.contains(&self.size.x) || !edges.contains(&self.size.y) {
+            bail!(
+                "board must be between {min}x{min} and {max}x{max}, got {}",
+                self.size,
+                min = MIN_BOARD_EDGE,
+                max = MAX_BOARD_EDGE
+            );
+        }
+
+        if let BoneCount::Density(d) = self.bones {
+            if !d.is_finite() || d <= 0.0 {
+                bail!("bone density must be a positive number");
+            }
+        }
+
+        let bones = self.bones.resolve(self.size);
+        let max_bones = (self.size.count() as f32 * self.max_density()) as usize;
+        if bones == 0 || bones > max_bones {
+            bail!(
+                "a {} board must have between 1 and {} bones, got {}",
+                self.size,
+                max_bones,
+                bones
+            );
+        }
+
+        if let Spawn::Fixed(p) = self.spawn {
+            if !Area::from(self.size).contains(p) {
+                bail!("spawn {} is outside of the {} board", p, self.size);
+            }
+        }
+
+        if let Some(turns) = self.rules.turns {
+            if turns.moves == 0 {
+                bail!("players need at least one move per turn");
+            }
+
+            let timeouts = MIN_TURN_TIMEOUT_SECS..=MAX_TURN_TIMEOUT_SECS;
+            if !timeouts.contains(&turns.timeout_secs) {
+                bail!(
+                    "turns must last between {} and {} seconds, got {}",
+                    MIN_TURN_TIMEOUT_SECS,
+                    MAX_TURN_TIMEOUT_SECS,
+                    turns.timeout_secs
+                );
+            }
+        }
+
+        if let Some(limit) = self.rules.time_limit_secs {
+            if !(MIN_TIME_LIMIT_SECS..=MAX_TIME_LIMIT_SECS).contains(&limit) {
+                bail!(
+                    "time limit must be between {} and {} seconds, got {}",
+                    MIN_TIME_LIMIT_SECS,
+                    MAX_TIME_LIMIT_SECS,
+                    limit
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How much of the board may be bones, depending on what the generator has to guarantee. The
+    /// no-guess limits keep even the unluckiest seed well within the generator's attempts.
+    fn max_density(&self) -> f32 {
+        let short_edge = self.size.x.min(self.size.y);
+
+        if !self.rules.no_guess {
+            MAX_BONE_DENSITY
+        } else if short_edge < THIN_BOARD_EDGE {
+            MAX_THIN_NO_GUESS_DENSITY
+        } else if short_edge < NARROW_BOARD_EDGE {
+            MAX_NARROW_NO_GUESS_DENSITY
+        } else if self.size.count() > LARGE_BOARD_CELLS {
+            MAX_LARGE_NO_GUESS_DENSITY
+        } else {
+            MAX_NO_GUESS_DENSITY
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            size: Size { x: 10, y: 10 },
+            bones: BoneCount::Count(15),
+            spawn: Spawn::Fixed(Point { x: 5, y: 5 }),
+            rules: Rules::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Named presets matching the classic minesweeper difficulties
+pub enum Difficulty {
+    Beginner,
+    Intermediate,
+    Expert,
+}
+
+impl Difficulty {
+    pub fn config(&self) -> GameConfig {
+        let (size, bones) = match self {
+            Self::Beginner => (Size { x: 9, y: 9 }, 10),
+            Self::Intermediate => (Size { x: 16, y: 16 }, 40),
+            Self::Expert => (Size { x: 30, y: 16 }, 99),
+        };
+
+        GameConfig {
+            size,
+            bones: BoneCount::Count(bones),
+            spawn: Spawn::Fixed(Point {
+                x: size.x as i32 / 2,
+                y: size.y as i32 / 2,
+            }),
+            rules: Rules::default(),
+        }
+    }
+}
+
+impl From<Difficulty> for GameConfig {
+    fn from(value: Difficulty) -> Self {
+        value.config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_within_the_limits() {
+        for difficulty in [
+            Difficulty::Beginner,
+            Difficulty::Intermediate,
+            Difficulty::Expert,
+        ] {
+            assert!(difficulty.config().validate().is_ok(), "{:?}", difficulty);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::{Difficulty, GameConfig},
+    digsites::{DigSite, GameStatus},
+    seed::Seed,
+};
+
+/// How many finished runs are kept per day
+const LEADERBOARD_SIZE: usize = 100;
+/// How many days of leaderboards are kept around before the oldest is dropped
+const LEADERBOARD_DAYS: u64 = 7;
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A daily challenge, identified by the number of days since the unix epoch. Days roll over at
+/// midnight UTC so everyone in the world plays the same board on the same day.
+pub struct Daily(pub u64);
+
+impl Daily {
+    pub fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Daily(secs / SECONDS_PER_DAY)
+    }
+
+    /// The seed is a scrambled version of the day so neighbouring days don't look alike. The
+    /// server's `secret` is mixed in, without it anyone could generate the board offline.
+    pub fn seed(&self, secret: Seed) -> Seed {
+        // splitmix64
+        let mut z = (self.0 ^ secret.0).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Seed(z ^ (z >> 31))
+    }
+
+    /// Boards get harder towards the weekend
+    pub fn config(&self) -> GameConfig {
+        // 1970-01-01 was a thursday, so shift monday to 0
+        let weekday = (self.0 + 3) % 7;
+        match weekday {
+            0 | 1 => Difficulty::Beginner,
+            2..=4 => Difficulty::Intermediate,
+            _ => Difficulty::Expert,
+        }
+        .config()
+    }
+
+    /// Year, month and day of the challenge in the proleptic gregorian calendar
+    fn civil(&self) -> (i64, u64, u64) {
+        // Howard Hinnant's days_from_civil, inverted
+        let z = self.0 as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097) as u64;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe as i64 + era * 400 + i64::from(month <= 2);
+        (year, month, day)
+    }
+}
+
+impl fmt::Display for Daily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.civil();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A finished daily run
+pub struct DailyEntry {
+    pub party: String,
+    pub players: Vec<String>,
+    /// Milliseconds from the board being generated until it was cleared, as measured by the
+    /// server
+    pub time: u64,
+    pub bones: usize,
+    pub finished_at: u64,
+}
+
+impl DailyEntry {
+    /// Only won daily games make it onto the leaderboard
+    pub fn from_game(party: String, game: &DigSite) -> Option<(Daily, Self)> {
+        let daily = game.daily()?;
+        let GameStatus::Won { at, .. } = game.status() else {
+            return None;
+        };
+
+        let mut players = game.player_ids();
+        players.sort();
+
+        Some((
+            daily,
+            DailyEntry {
+                party,
+                players,
+                time: game.elapsed(*at),
+                bones: game.bones_found(),
+                finished_at: *at,
+            },
+        ))
+    }
+}
+
+/// In-process leaderboard for the daily challenges. This lives outside of any party so results
+/// are kept after everyone has left.
+pub struct Leaderboard {
+    entries: DashMap<Daily, Vec<DailyEntry>>,
+    /// Everyone that started each daily. The board is the same every time, so nobody gets a second
+    /// go at one they have already seen.
+    started: DashMap<Daily, HashSet<String>>,
+}
+
+impl Leaderboard {
+    pub fn new() -> Self {
+        Leaderboard {
+            entries: DashMap::new(),
+            started: DashMap::new(),
+        }
+    }
+
+    /// Note that `players` are starting the daily. Nobody is noted if any of them already had
+    /// their go.
+    pub fn start(&self, daily: Daily, players: &[String]) -> Result<()> {
+        {
+            let mut started = self.started.entry(daily).or_default();
+            if let Some(player) = players.iter().find(|p| started.contains(*p)) {
+                bail!(
+                    "{} already played the daily challenge for {}",
+                    player,
+                    daily
+                );
+            }
+            started.extend(players.iter().cloned());
+        }
+
+        self.prune(daily);
+
+        Ok(())
+    }
+
+    /// Only the first finished run of every player counts
+    pub fn record(&self, daily: Daily, entry: DailyEntry) {
+        {
+            let mut entries = self.entries.entry(daily).or_default();
+            let repeat = entries
+                .iter()
+                .any(|e| e.players.iter().any(|p| entry.players.contains(p)));
+            if repeat {
+                return;
+            }
+
+            entries.push(entry);
+            entries.sort_by(|a, b| a.time.cmp(&b.time).then(b.bones.cmp(&a.bones)));
+            entries.truncate(LEADERBOARD_SIZE);
+        }
+
+        self.prune(daily);
+    }
+
+    /// Best runs of the day, fastest first
+    pub fn top(&self, daily: Daily) -> Vec<DailyEntry> {
+        self.entries
+            .get(&daily)
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+
+    /// Drop every day that is too old to be kept around
+    fn prune(&self, daily: Daily) {
+        let cutoff = daily.0.saturating_sub(LEADERBOARD_DAYS);
+        self.entries.retain(|day, _| day.0 > cutoff);
+        self.started.retain(|day, _| day.0 > cutoff);
+    }
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Leaderboard::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(party: &str, players: &[&str], time: u64) -> DailyEntry {
+        DailyEntry {
+            party: party.to_string(),
+            players: players.iter().map(|p| p.to_string()).collect(),
+            time,
+            bones: 0,
+            finished_at: 0,
+        }
+    }
+
+    #[test]
+    fn players_only_get_one_go() {
+        let leaderboard = Leaderboard::new();
+        let daily = Daily(20_000);
+        let party = vec![String::from("a"), String::from("b")];
+
+        assert!(leaderboard.start(daily, &party).is_ok());
+        assert!(leaderboard.start(daily, &[String::from("b")]).is_err());
+        assert!(leaderboard.start(Daily(20_001), &party).is_ok());
+    }
+
+    #[test]
+    fn only_the_first_run_is_recorded() {
+        let leaderboard = Leaderboard::new();
+        let daily = Daily(20_000);
+
+        leaderboard.record(daily, entry("one", &["a", "b"], 5_000));
+        leaderboard.record(daily, entry("two", &["b"], 1_000));
+        leaderboard.record(daily, entry("three", &["c"], 9_000));
+
+        let parties: Vec<_> = leaderboard
+            .top(daily)
+            .into_iter()
+            .map(|e| e.party)
+            .collect();
+        assert_eq!(parties, ["one", "three"]);
+    }
+}
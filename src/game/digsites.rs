@@ -2,19 +2,34 @@ use anyhow::{anyhow, bail, Ok, Result};
 use bitvec::vec::BitVec;
 use rand::{prelude::*, seq::index::sample};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fmt::{self, Debug},
-    usize,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::geometry::{Area, Point, Size};
+use crate::geometry::{point::EMPTY_POINT, Area, Point, Size};
+
+use super::{
+    config::{GameConfig, Rules, Visibility},
+    daily::Daily,
+    seed::Seed,
+    solver::Solver,
+    turns::{NotYourTurn, Turn},
+};
+
+/// How many random layouts to try before giving up on finding one that needs no guessing. This is
+/// the only bound, so whether a seed works never depends on how fast the server is.
+const MAX_GENERATION_ATTEMPTS: usize = 1000;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
-enum Cell {
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Cell {
     Bone,
     Empty(u8),
+    /// A bone that has already been dug up by a player. Safe to stand on.
+    Excavated,
 }
 
 impl Cell {
@@ -25,6 +40,7 @@ impl Cell {
                 _ => format!("{}", v),
             },
             Self::Bone => "b".to_string(),
+            Self::Excavated => "x".to_string(),
         }
     }
 }
@@ -37,13 +53,131 @@ impl fmt::Display for Cell {
 
 type Board = Vec<Cell>;
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkKind {
+    /// The player is sure there is a bone here
+    Flag,
+    /// The player suspects there might be a bone here
+    Question,
+}
+
+impl MarkKind {
+    fn symbol(&self) -> String {
+        match self {
+            Self::Flag => "F".to_string(),
+            Self::Question => "?".to_string(),
+        }
+    }
+}
+
+/// A player annotation on a hidden cell
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+struct Mark {
+    kind: MarkKind,
+    by: String,
+}
+
+type Marks = Vec<Option<Mark>>;
+
+/// What a single player knows about the board when playing with per-player fog
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct View {
+    state: BitVec,
+    marks: Marks,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Player {
     id: String,
     pos: Point,
+    /// Bones this player has excavated during the current run
+    #[serde(default)]
+    bones: usize,
+    /// False while the player is gone but their party is waiting for them to reconnect
+    #[serde(default)]
+    connected: bool,
 }
 type Players = HashMap<String, Player>;
 
+#[derive(Debug, Serialize, Clone)]
+struct Score {
+    id: String,
+    bones: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Where a run currently stands. Once a game is won or lost it stays that way until a new board is
+/// generated. `by` is the id of the player that ended the game and `at` is the server time in
+/// milliseconds since the unix epoch.
+pub enum GameStatus {
+    InProgress,
+    Won {
+        by: String,
+        at: u64,
+    },
+    Lost {
+        by: String,
+        pos: Point,
+        at: u64,
+    },
+    /// The time limit ran out before the board was cleared
+    TimeUp {
+        at: u64,
+    },
+}
+
+impl GameStatus {
+    pub fn is_over(&self) -> bool {
+        !matches!(self, Self::InProgress)
+    }
+
+    /// Server time the game ended at
+    pub fn ended_at(&self) -> Option<u64> {
+        match self {
+            Self::InProgress => None,
+            Self::Won { at, .. } | Self::Lost { at, .. } | Self::TimeUp { at } => Some(*at),
+        }
+    }
+}
+
+/// Server time in milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+/// The game clock as kept by the server. All times are in milliseconds, `started_at` since the
+/// unix epoch.
+pub struct Clock {
+    pub started_at: u64,
+    /// Time played so far, not counting pauses
+    pub elapsed: u64,
+    pub time_limit: Option<u64>,
+    pub paused: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+/// Everything that changed on a board since the last patch was taken
+pub struct Changes {
+    cells: BTreeSet<usize>,
+    /// Cells that changed in a player's private view, only that player is told about them
+    private: HashMap<String, BTreeSet<usize>>,
+    players: BTreeSet<String>,
+    /// Something about the game as a whole changed, like being paused
+    game: bool,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.private.is_empty() && self.players.is_empty() && !self.game
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// Digsite is a complete structure around the game board and state.
 /// It contains the board, the dimensions, the initial position and the bones. Anything needed to
@@ -52,31 +186,100 @@ pub struct DigSite {
     dimensions: Size,
     board: Board,
     state: BitVec,
+    marks: Marks,
+    /// Private visibility for every player, only used with [Visibility::PerPlayer]. The shared
+    /// `state` then only holds the starting area every view begins from.
+    #[serde(default)]
+    views: HashMap<String, View>,
 
     players: Players,
     spawn_pos: Option<Point>,
+    #[serde(default)]
+    rules: Rules,
+    seed: Option<Seed>,
+    #[serde(default)]
+    daily: Option<Daily>,
+    /// Server time the board was generated, in milliseconds since the unix epoch
+    #[serde(default)]
+    started_at: u64,
+    status: GameStatus,
+    #[serde(default)]
+    paused: bool,
+    /// Server time the current pause started
+    #[serde(default)]
+    paused_at: Option<u64>,
+    /// How long the game was paused for before the current pause, in milliseconds
+    #[serde(default)]
+    paused_for: u64,
+    /// Only set when playing with [TurnRules](super::config::TurnRules)
+    #[serde(default)]
+    turn: Option<Turn>,
+
+    /// Bumped every time a patch is taken so clients can tell if they missed one
+    #[serde(default)]
+    version: u64,
+    #[serde(skip)]
+    changes: Changes,
 }
 
-#[derive(Debug, Serialize, Clone, Copy)]
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "snake_case")]
 enum CellState {
     Visible(Cell),
     Hidden,
+    Marked(Mark),
 }
 
 /// Use a seperate struct to output the state of the board for players to parse on the frontend.
 #[derive(Debug, Serialize, Clone)]
 pub struct DigSiteOutput {
     board: Vec<Vec<CellState>>,
+    /// Bones buried on the whole board, including ones that were already dug up
+    bones: usize,
+    players: Players,
+    scoreboard: Vec<Score>,
+    /// Only sent once the game is over, see [DigSite::public_seed]
+    seed: Option<Seed>,
+    daily: Option<Daily>,
+    status: GameStatus,
+    paused: bool,
+    turn: Option<Turn>,
+    clock: Clock,
+    version: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct CellPatch {
+    index: usize,
+    state: CellState,
+}
+
+/// Only what changed since the previous version. If a client's version isn't exactly one behind it
+/// missed something and should ask for the full [DigSiteOutput] again.
+#[derive(Debug, Serialize, Clone)]
+pub struct DigSitePatch {
+    version: u64,
+    cells: Vec<CellPatch>,
     players: Players,
+    /// Players that were taken out of the game since the previous version
+    removed: Vec<String>,
+    scoreboard: Vec<Score>,
+    /// Only sent once the game is over, see [DigSite::public_seed]
+    seed: Option<Seed>,
+    status: GameStatus,
+    paused: bool,
+    turn: Option<Turn>,
 }
 
 impl DigSite {
     fn symbol_at(&self, index: usize) -> Option<String> {
-        let visibility = *self.state.get(index)?;
+        let visibility = *self.visibility(None).get(index)?;
         Some(if visibility {
             let point = Area::from(self.dimensions).point_from_pos(index);
             let cell = self.get(point)?;
             format!("{}", cell)
+        } else if let Some(mark) = self.marks_for(None).get(index)? {
+            mark.kind.symbol()
         } else {
             "#".to_string()
         })
@@ -95,86 +298,721 @@ impl DigSite {
         BitVec::from_vec(vec![0; count])
     }
 
+    fn build_marks(count: usize) -> Marks {
+        vec![None; count]
+    }
+
     pub fn new(size: Size) -> Self {
         let count = size.count();
 
         let board = DigSite::build_board(count);
         let state = DigSite::build_state(count);
+        let marks = DigSite::build_marks(count);
         let players = HashMap::new();
 
         DigSite {
             dimensions: size,
             board,
             state,
+            marks,
+            views: HashMap::new(),
             players,
             spawn_pos: None,
+            rules: Rules::default(),
+            seed: None,
+            daily: None,
+            started_at: now_millis(),
+            status: GameStatus::InProgress,
+            paused: false,
+            paused_at: None,
+            paused_for: 0,
+            turn: None,
+            version: 0,
+            changes: Changes::default(),
         }
     }
 
-    pub fn generate<R: Rng>(
-        rng: &mut R,
-        size: Size,
-        bones: usize,
-        initial_pos: Point,
-    ) -> Result<Self> {
+    /// Generate a board from a validated [GameConfig]. With the `no_guess` rule the board can be
+    /// cleared from the spawn without ever having to guess, layouts are rolled until the [Solver]
+    /// manages to finish one. This can take a while, keep it off the async runtime.
+    pub fn generate<R: Rng>(rng: &mut R, config: &GameConfig) -> Result<Self> {
+        config.validate()?;
+
+        let size = config.size;
+        let bones = config.bones.resolve(size);
+
+        if !config.rules.no_guess {
+            let initial_pos = config.spawn.resolve(rng, size);
+            return DigSite::layout(rng, config, initial_pos);
+        }
+
+        for _ in 0..MAX_GENERATION_ATTEMPTS {
+            let initial_pos = config.spawn.resolve(rng, size);
+            let mut ds = DigSite::layout(rng, config, initial_pos)?;
+            if Solver::new(ds.dimensions, &ds.board, &ds.state).solve() {
+                ds.started_at = now_millis();
+                return Ok(ds);
+            }
+        }
+
+        bail!(
+            "unable to generate a {} board with {} bones that can be solved without guessing",
+            size,
+            bones
+        )
+    }
+
+    /// Generate a board that can be reproduced later from the same seed and config
+    pub fn from_seed(seed: Seed, config: &GameConfig) -> Result<Self> {
+        let mut ds = DigSite::generate(&mut seed.rng(), config)?;
+        ds.seed = Some(seed);
+        Ok(ds)
+    }
+
+    /// Generate the shared board for a daily challenge, see [Daily::seed] for the `secret`
+    pub fn for_daily(daily: Daily, secret: Seed) -> Result<Self> {
+        let mut ds = DigSite::from_seed(daily.seed(secret), &daily.config())?;
+        ds.daily = Some(daily);
+        Ok(ds)
+    }
+
+    pub fn seed(&self) -> Option<Seed> {
+        self.seed
+    }
+
+    /// The seed as far as players may know it. The seed and config reproduce the whole board, so
+    /// it's kept secret until the game is over. The daily seed is shared by every party that day
+    /// and is never given out.
+    pub fn public_seed(&self) -> Option<Seed> {
+        self.seed
+            .filter(|_| self.status.is_over() && self.daily.is_none())
+    }
+
+    pub fn daily(&self) -> Option<Daily> {
+        self.daily
+    }
+
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    pub fn player_ids(&self) -> Vec<String> {
+        self.players.keys().cloned().collect()
+    }
+
+    pub fn has_player(&self, id: &str) -> bool {
+        self.players.contains_key(id)
+    }
+
+    /// Everyone holding a slot in the game, including players that disconnected and may come back
+    pub fn player_count(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Bones buried on the board, whether they have been dug up or not
+    pub fn bones(&self) -> usize {
+        self.board
+            .iter()
+            .filter(|c| matches!(c, Cell::Bone | Cell::Excavated))
+            .count()
+    }
+
+    /// Total bones excavated by every player
+    pub fn bones_found(&self) -> usize {
+        self.players.values().map(|p| p.bones).sum()
+    }
+
+    /// Randomly lay out the bones and reveal the starting area, with no regard for solvability
+    fn layout<R: Rng>(rng: &mut R, config: &GameConfig, initial_pos: Point) -> Result<Self> {
+        let size = config.size;
+        let bones = config.bones.resolve(size);
+
         let mut ds = DigSite::new(size);
 
         ds.spawn_pos = Some(initial_pos);
+        ds.rules = config.rules.clone();
 
         ds.board = DigSite::build_board(ds.dimensions.count());
         ds.state = DigSite::build_state(ds.dimensions.count());
+        ds.marks = DigSite::build_marks(ds.dimensions.count());
 
         ds.clear_cell_state()
             .generate_bones(rng, bones, initial_pos)?
             .apply_cell_state()?;
 
-        ds.flood_fill_visibility(initial_pos)?;
+        ds.flood_fill_visibility(initial_pos, None)?;
+        ds.changes = Changes::default();
 
         Ok(ds)
     }
 
     pub fn add_player(&mut self, id: String) -> Result<()> {
+        if self.rules.visibility == Visibility::PerPlayer {
+            let count = self.dimensions.count();
+            self.views.entry(id.clone()).or_insert_with(|| View {
+                state: self.state.clone(),
+                marks: DigSite::build_marks(count),
+            });
+        }
+
         // TODO: Change this to adapt for upcoming changed player schema
-        self.players.entry(id.clone()).or_insert(Player {
-            id,
-            pos: self.spawn_pos.ok_or(anyhow!(
-                "no spawn point provided. was the board generated correctly?"
-            ))?,
-        });
+        let spawn_pos = self.spawn_pos.ok_or(anyhow!(
+            "no spawn point provided. was the board generated correctly?"
+        ))?;
+
+        // Returning players keep their position and score
+        self.players
+            .entry(id.clone())
+            .or_insert(Player {
+                id: id.clone(),
+                pos: spawn_pos,
+                bones: 0,
+                connected: true,
+            })
+            .connected = true;
+        self.changes.players.insert(id.clone());
+
+        if self.rules.turns.is_some() {
+            let turn = self.turn.get_or_insert_with(Turn::default);
+            turn.join(&id);
+            if turn.player().is_none() {
+                self.advance_turn(None);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take a player out of the game entirely, along with their view and score
+    pub fn remove_player(&mut self, id: &str) {
+        if self.players.remove(id).is_some() {
+            self.views.remove(id);
+            self.changes.players.insert(id.to_string());
+        }
+
+        if self.turn.as_ref().is_some_and(|t| t.is_turn_of(id)) {
+            self.advance_turn(Some(id));
+        }
+        if let Some(turn) = self.turn.as_mut() {
+            turn.leave(id);
+        }
+    }
+
+    /// Flag a player as gone without removing them, so they can pick up where they left off
+    pub fn disconnect_player(&mut self, id: &str) {
+        if let Some(player) = self.players.get_mut(id) {
+            player.connected = false;
+            self.changes.players.insert(id.to_string());
+        }
+
+        if self.turn.as_ref().is_some_and(|t| t.is_turn_of(id)) {
+            self.advance_turn(Some(id));
+        }
+    }
+
+    /// Flag everyone as gone, used for restored games that nobody has rejoined yet
+    pub fn disconnect_all(&mut self) {
+        for (id, player) in self.players.iter_mut() {
+            player.connected = false;
+            self.changes.players.insert(id.clone());
+        }
+
+        if self.turn.is_some() {
+            self.advance_turn(None);
+        }
+    }
+
+    /// Treat the time between `since` and `now` as paused, so a game doesn't run on while the
+    /// server is down
+    pub fn skip_downtime(&mut self, since: u64, now: u64) {
+        if self.status.is_over() || self.paused {
+            return;
+        }
+
+        self.paused_for += now.saturating_sub(since);
+    }
+
+    pub fn turn(&self) -> Option<&Turn> {
+        self.turn.as_ref()
+    }
+
+    /// Pass the turn on to the next connected player, never to `skip`
+    fn advance_turn(&mut self, skip: Option<&str>) {
+        let Some(rules) = self.rules.turns else {
+            return;
+        };
+        let players = &self.players;
+        if let Some(turn) = self.turn.as_mut() {
+            turn.advance(&rules, now_millis(), |id| {
+                Some(id) != skip && players.get(id).is_some_and(|p| p.connected)
+            });
+            self.changes.game = true;
+        }
+    }
+
+    /// Refuse actions from anyone but the current player in turn based games
+    fn check_turn(&self, id: &str) -> Result<()> {
+        if self.turn.as_ref().is_some_and(|t| !t.is_turn_of(id)) {
+            bail!(NotYourTurn);
+        }
+
+        Ok(())
+    }
+
+    /// Count an action against the current turn and pass it on once the budget is used up
+    fn spend_move(&mut self) {
+        let Some(turn) = self.turn.as_mut() else {
+            return;
+        };
+
+        self.changes.game = true;
+        if turn.spend() {
+            self.advance_turn(None);
+        }
+    }
+
+    /// Pass the turn on if its time ran out by `now`. Returns true if it did.
+    pub fn expire_turn(&mut self, now: u64) -> bool {
+        if self.status.is_over() || self.paused {
+            return false;
+        }
+
+        let expired = self
+            .turn
+            .as_ref()
+            .is_some_and(|t| t.player().is_some() && now >= t.ends_at());
+        if expired {
+            self.advance_turn(None);
+        }
+
+        expired
+    }
+
+    pub fn status(&self) -> &GameStatus {
+        &self.status
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Freeze or resume the game. Nobody can act while it's paused and the clock stops. Daily
+    /// challenges can't be paused, the board would stay visible while the clock is stopped.
+    pub fn set_paused(&mut self, paused: bool) -> Result<()> {
+        if self.status.is_over() {
+            bail!("game is already over");
+        }
+        if self.daily.is_some() {
+            bail!("daily challenges can't be paused");
+        }
+        if self.paused == paused {
+            return Ok(());
+        }
+
+        let now = now_millis();
+        self.paused = paused;
+        self.changes.game = true;
+
+        if paused {
+            self.paused_at = Some(now);
+            return Ok(());
+        }
+
+        if let Some(since) = self.paused_at.take() {
+            self.paused_for += now.saturating_sub(since);
+        }
+
+        // Time spent paused doesn't count against whoever's turn it is
+        if let (Some(rules), Some(turn)) = (self.rules.turns, self.turn.as_mut()) {
+            turn.restart(&rules, now);
+        }
+
+        Ok(())
+    }
+
+    /// Milliseconds played by `now`, not counting pauses. Stops counting once the game is over.
+    pub fn elapsed(&self, now: u64) -> u64 {
+        let end = self.status.ended_at().unwrap_or(now).min(now);
+        let end = self.paused_at.map_or(end, |since| end.min(since));
+        end.saturating_sub(self.started_at)
+            .saturating_sub(self.paused_for)
+    }
+
+    pub fn clock(&self, now: u64) -> Clock {
+        Clock {
+            started_at: self.started_at,
+            elapsed: self.elapsed(now),
+            time_limit: self.rules.time_limit_secs.map(|s| s * 1000),
+            paused: self.paused,
+        }
+    }
+
+    /// End the game if its time limit ran out by `now`. Returns true if it did.
+    pub fn expire_time(&mut self, now: u64) -> bool {
+        let Some(limit) = self.rules.time_limit_secs else {
+            return false;
+        };
+        if self.status.is_over() || self.paused || self.elapsed(now) < limit * 1000 {
+            return false;
+        }
+
+        self.status = GameStatus::TimeUp { at: now };
+        self.changes.game = true;
+
+        true
+    }
+
+    /// Actions are only accepted while the game is running
+    fn ensure_playable(&self) -> Result<()> {
+        if self.status.is_over() {
+            bail!("game is already over");
+        }
+        if self.paused {
+            bail!("game is paused");
+        }
 
         Ok(())
     }
 
+    /// Whether every player has their own view of the board and needs their own output
+    pub fn has_private_views(&self) -> bool {
+        self.rules.visibility == Visibility::PerPlayer
+    }
+
     pub fn move_player(&mut self, id: String, p: Point) -> Result<()> {
-        self.players.entry(id).and_modify(|player| {
+        self.ensure_playable()?;
+        self.check_turn(&id)?;
+
+        self.players.entry(id.clone()).and_modify(|player| {
             player.pos = Area::from(self.dimensions).clamp_point(player.pos + p)
         });
+        self.changes.players.insert(id.clone());
 
-        self.step()
+        self.step(&id)?;
+        self.spend_move();
+
+        Ok(())
+    }
+
+    /// Dig at a cell next to or under the player. Digging is only safe where the player knows what
+    /// is underneath: a flagged bone is collected and credited to the player, but digging blindly
+    /// into a bone or finding nothing under a flag loses the game, just like stepping on a bone.
+    pub fn excavate(&mut self, id: String, offset: Point) -> Result<()> {
+        self.ensure_playable()?;
+        self.check_turn(&id)?;
+
+        let target = self.reach(&id, offset)?;
+        let hidden = self
+            .is_hidden(target, Some(&id))
+            .ok_or(anyhow!("unable to tell if excavated cell is hidden"))?;
+        let flagged = hidden && self.is_flagged(target, Some(&id));
+
+        match self
+            .get(target)
+            .ok_or(anyhow!("excavated cell out of range"))?
+        {
+            Cell::Bone if hidden && !flagged => {
+                self.reveal(self.pos_from_point(target), Some(&id));
+                self.lose(&id, target);
+            }
+            Cell::Empty(_) if flagged => {
+                self.flood_fill_visibility(target, Some(&id))?;
+                self.lose(&id, target);
+            }
+            Cell::Bone => {
+                self.set(target, Cell::Excavated)?;
+                self.reveal(self.pos_from_point(target), Some(&id));
+                if let Some(player) = self.players.get_mut(&id) {
+                    player.bones += 1;
+                }
+                self.changes.players.insert(id.clone());
+            }
+            Cell::Empty(_) => self.flood_fill_visibility(target, Some(&id))?,
+            Cell::Excavated => bail!("this bone has already been excavated"),
+        }
+
+        self.step(&id)?;
+        self.spend_move();
+
+        Ok(())
+    }
+
+    /// Reveal the neighbours of a numbered cell next to or under the player once the same number
+    /// of neighbours has been flagged or dug up. A wrong flag means a bone gets uncovered and the
+    /// game is lost.
+    pub fn chord(&mut self, id: String, offset: Point) -> Result<()> {
+        self.ensure_playable()?;
+        self.check_turn(&id)?;
+
+        if !self.rules.chording {
+            bail!("chording is disabled for this game");
+        }
+
+        let target = self.reach(&id, offset)?;
+
+        if self
+            .is_hidden(target, Some(&id))
+            .ok_or(anyhow!("unable to tell if chorded cell is hidden"))?
+        {
+            bail!("only revealed cells can be chorded");
+        }
+
+        let Some(Cell::Empty(n)) = self.get(target) else {
+            bail!("only numbered cells can be chorded");
+        };
+        if n == 0 {
+            bail!("only numbered cells can be chorded");
+        }
+
+        let (hidden, revealed): (Vec<_>, Vec<_>) = self
+            .neighbours(target)
+            .into_iter()
+            .partition(|p| self.is_hidden(*p, Some(&id)).unwrap_or(false));
+
+        // Bones that were already dug up still count towards the number
+        let excavated = revealed
+            .iter()
+            .filter(|p| matches!(self.get(**p), Some(Cell::Excavated)))
+            .count();
+
+        let (flagged, unflagged): (Vec<_>, Vec<_>) = hidden
+            .into_iter()
+            .partition(|p| self.is_flagged(*p, Some(&id)));
+
+        if flagged.len() + excavated != n as usize {
+            bail!("number of flags does not match the cell");
+        }
+
+        for p in unflagged {
+            match self.get(p).ok_or(anyhow!("chorded cell out of range"))? {
+                Cell::Bone => {
+                    self.reveal(self.pos_from_point(p), Some(&id));
+                    self.lose(&id, p);
+                }
+                _ => self.flood_fill_visibility(p, Some(&id))?,
+            }
+        }
+
+        self.step(&id)?;
+        self.spend_move();
+
+        Ok(())
+    }
+
+    /// Resolve a cell next to or under a player into a position on the board
+    fn reach(&self, id: &str, offset: Point) -> Result<Point> {
+        if !Area::around_point(EMPTY_POINT, 1).contains(offset) {
+            bail!("can only reach cells next to the player");
+        }
+
+        let pos = self
+            .players
+            .get(id)
+            .ok_or(anyhow!("player is not part of this game"))?
+            .pos;
+        let target = pos + offset;
+
+        if !self.in_bounds(target) {
+            bail!("tried to reach a cell out of range");
+        }
+
+        Ok(target)
+    }
+
+    /// All the in-bounds cells surrounding a point, not including the point itself
+    fn neighbours(&self, p: Point) -> Vec<Point> {
+        let dim_area = Area::from(self.dimensions);
+        let area = dim_area.intersecting_area(Area::around_point(p, 1));
+
+        let cell_count = Size::from(area).count();
+        let area_normalized = area.normalize();
+        let area_offset = area.0;
+
+        (0..cell_count)
+            .map(|pos| area_normalized.point_from_pos(pos) + area_offset)
+            .filter(|n| *n != p)
+            .collect()
+    }
+
+    /// Place or clear a mark on a hidden cell. Passing `None` removes whatever mark is there.
+    pub fn set_mark(&mut self, id: String, p: Point, kind: Option<MarkKind>) -> Result<()> {
+        self.ensure_playable()?;
+
+        if !self.players.contains_key(&id) {
+            bail!("player is not part of this game");
+        }
+
+        if !self.in_bounds(p) {
+            bail!("tried to mark cell out of range");
+        }
+
+        if !self
+            .is_hidden(p, Some(&id))
+            .ok_or(anyhow!("unable to tell if marked cell is hidden"))?
+        {
+            bail!("only hidden cells can be marked");
+        }
+
+        let index = self.pos_from_point(p);
+        let mark = kind.map(|kind| Mark {
+            kind,
+            by: id.clone(),
+        });
+        self.marks_for_mut(Some(&id))[index] = mark;
+        self.cell_changed(index, Some(&id));
+
+        Ok(())
+    }
+
+    /// Make a cell visible to `who`. Marks are dropped since there is nothing left to guess.
+    fn reveal(&mut self, index: usize, who: Option<&str>) {
+        self.visibility_mut(who).set(index, true);
+        self.cell_changed(index, who);
+        if let Some(mark) = self.marks_for_mut(who).get_mut(index) {
+            *mark = None;
+        }
+    }
+
+    /// Note that a cell changed as seen by `who`, so the next patch includes it
+    fn cell_changed(&mut self, index: usize, who: Option<&str>) {
+        match who.filter(|id| self.views.contains_key(*id)) {
+            Some(id) => {
+                self.changes
+                    .private
+                    .entry(id.to_string())
+                    .or_default()
+                    .insert(index);
+            }
+            None => {
+                self.changes.cells.insert(index);
+            }
+        }
+    }
+
+    /// The visibility `who` sees. Everyone shares the same state unless the game is played with
+    /// per-player fog, `None` always means the shared state.
+    fn visibility(&self, who: Option<&str>) -> &BitVec {
+        match who.and_then(|id| self.views.get(id)) {
+            Some(view) => &view.state,
+            None => &self.state,
+        }
+    }
+
+    fn visibility_mut(&mut self, who: Option<&str>) -> &mut BitVec {
+        match who.and_then(|id| self.views.get_mut(id)) {
+            Some(view) => &mut view.state,
+            None => &mut self.state,
+        }
+    }
+
+    /// The marks `who` can see, following the same rules as [DigSite::visibility]
+    fn marks_for(&self, who: Option<&str>) -> &Marks {
+        match who.and_then(|id| self.views.get(id)) {
+            Some(view) => &view.marks,
+            None => &self.marks,
+        }
+    }
+
+    fn marks_for_mut(&mut self, who: Option<&str>) -> &mut Marks {
+        match who.and_then(|id| self.views.get_mut(id)) {
+            Some(view) => &mut view.marks,
+            None => &mut self.marks,
+        }
+    }
+
+    fn scoreboard(&self) -> Vec<Score> {
+        let mut scores: Vec<_> = self
+            .players
+            .values()
+            .map(|p| Score {
+                id: p.id.clone(),
+                bones: p.bones,
+            })
+            .collect();
+
+        scores.sort_by(|a, b| b.bones.cmp(&a.bones).then_with(|| a.id.cmp(&b.id)));
+        scores
     }
 
     /// Step the game forward. Run checks and win-conditions or calculate whatevers needed.
-    fn step(&mut self) -> Result<()> {
-        let players: Vec<_> = self.players.values().map(|p| p.pos).collect();
-        for pos in players {
+    /// `actor` is the player whose action caused this step and is credited if it clears the board.
+    fn step(&mut self, actor: &str) -> Result<()> {
+        let players: Vec<_> = self
+            .players
+            .values()
+            .map(|p| (p.id.clone(), p.pos))
+            .collect();
+        for (id, pos) in players {
             if self
-                .is_hidden(pos)
-                .ok_or(anyhow!("unable to tell if player position is valid"))?
+                .is_hidden(pos, Some(&id))
+                .ok_or_else(|| anyhow!("unable to tell if player position is valid"))?
             {
-                self.flood_fill_visibility(pos)?;
+                self.flood_fill_visibility(pos, Some(&id))?;
             }
         }
 
+        if self.status.is_over() {
+            return Ok(());
+        }
+
+        let dug_bone = self
+            .players
+            .values()
+            .find(|p| matches!(self.get(p.pos), Some(Cell::Bone)));
+
+        if let Some(player) = dug_bone {
+            self.status = GameStatus::Lost {
+                by: player.id.clone(),
+                pos: player.pos,
+                at: now_millis(),
+            };
+        } else if self.is_cleared(Some(actor)) {
+            self.status = GameStatus::Won {
+                by: actor.to_string(),
+                at: now_millis(),
+            };
+        }
+
         Ok(())
     }
 
-    fn is_hidden(&self, p: Point) -> Option<bool> {
+    /// Every cell that isn't a bone has been revealed to `who`
+    fn is_cleared(&self, who: Option<&str>) -> bool {
+        self.board
+            .iter()
+            .zip(self.visibility(who).iter())
+            .all(|(cell, visible)| matches!(cell, Cell::Bone) || *visible)
+    }
+
+    fn is_hidden(&self, p: Point, who: Option<&str>) -> Option<bool> {
         let pos = self.pos_from_point(p);
-        let spot = *self.state.get(pos)?;
+        let spot = *self.visibility(who).get(pos)?;
         Some(!spot)
     }
 
-    fn in_bounds(&self, p: Point) -> bool {
+    /// Whether `who` has flagged a cell as a bone
+    fn is_flagged(&self, p: Point, who: Option<&str>) -> bool {
+        matches!(
+            self.marks_for(who).get(self.pos_from_point(p)),
+            Some(Some(Mark {
+                kind: MarkKind::Flag,
+                ..
+            }))
+        )
+    }
+
+    /// End the game because `id` uncovered something they shouldn't have at `pos`
+    fn lose(&mut self, id: &str, pos: Point) {
+        if !self.status.is_over() {
+            self.status = GameStatus::Lost {
+                by: id.to_string(),
+                pos,
+                at: now_millis(),
+            };
+        }
+    }
+
+    pub fn in_bounds(&self, p: Point) -> bool {
         Area::from(self.dimensions).contains(p)
     }
 
@@ -216,7 +1054,9 @@ impl DigSite {
                 continue;
             }
 
-            let cell = self.get(point).ok_or(anyhow!("placed bone out of range"))?;
+            let cell = self
+                .get(point)
+                .ok_or_else(|| anyhow!("placed bone out of range"))?;
 
             self.set(
                 point,
@@ -241,9 +1081,9 @@ impl DigSite {
 
                 match self
                     .get(board_point)
-                    .ok_or(anyhow!("accessing area around bone inaccessable"))?
+                    .ok_or_else(|| anyhow!("accessing area around bone inaccessable"))?
                 {
-                    Cell::Bone => continue,
+                    Cell::Bone | Cell::Excavated => continue,
                     Cell::Empty(v) => self.set(board_point, Cell::Empty(v + 1))?,
                 }
             }
@@ -252,22 +1092,22 @@ impl DigSite {
         Ok(())
     }
 
-    fn flood_fill_visibility(&mut self, p: Point) -> Result<()> {
+    fn flood_fill_visibility(&mut self, p: Point, who: Option<&str>) -> Result<()> {
         let index = self.pos_from_point(p);
 
         let cell = self
             .get(p)
-            .ok_or(anyhow!("Board is not synced with expected state size"))?;
+            .ok_or_else(|| anyhow!("Board is not synced with expected state size"))?;
 
-        if index >= self.state.len() {
+        if index >= self.visibility(who).len() {
             bail!("State is not synced with expected board size");
         }
 
-        if self.state[index] {
+        if self.visibility(who)[index] {
             return Ok(());
         }
 
-        self.state.set(index, true);
+        self.reveal(index, who);
 
         if matches!(cell, Cell::Empty(0)) {
             let dim_area = Area::from(self.dimensions);
@@ -280,7 +1120,7 @@ impl DigSite {
             for pos in 0..cell_count {
                 let local_point = area_normalized.point_from_pos(pos);
                 let board_point = local_point + area_offset;
-                self.flood_fill_visibility(board_point)?;
+                self.flood_fill_visibility(board_point, who)?;
             }
 
             Ok(())
@@ -318,6 +1158,14 @@ impl DigSite {
             })
             .collect();
 
+        if num_bones > potential_locations.len() {
+            bail!(
+                "cannot place {} bones in {} free cells",
+                num_bones,
+                potential_locations.len()
+            );
+        }
+
         // Randomly select positions to place bones, ensuring no duplication.
         let selected_positions = sample(rng, potential_locations.len(), num_bones);
 
@@ -325,7 +1173,7 @@ impl DigSite {
             self.set(
                 *potential_locations
                     .get(idx)
-                    .ok_or(anyhow!("invalid sample"))?,
+                    .ok_or_else(|| anyhow!("invalid sample"))?,
                 Cell::Bone,
             )?;
         }
@@ -377,7 +1225,7 @@ impl DigSite {
                 let board_point = local_point + bone_cell_offset;
                 let target_cell = self
                     .get(board_point)
-                    .ok_or(anyhow!("accessing area around bone oob"))?;
+                    .ok_or_else(|| anyhow!("accessing area around bone oob"))?;
 
                 if let Cell::Empty(v) = target_cell {
                     self.set(board_point, Cell::Empty(v + 1))?;
@@ -388,32 +1236,99 @@ impl DigSite {
         Ok(self)
     }
 
-    pub fn output(&self) -> DigSiteOutput {
-        let dim_area = Area::from(self.dimensions);
+    pub fn version(&self) -> u64 {
+        self.version
+    }
 
-        let board = self
-            .board
-            .iter()
-            .enumerate()
-            .map(|(i, cell)| {
-                if let Some(is_hidden) = self.is_hidden(dim_area.point_from_pos(i)) {
-                    if is_hidden {
-                        CellState::Hidden
-                    } else {
-                        CellState::Visible(*cell)
-                    }
-                } else {
-                    CellState::Hidden
-                }
+    /// Take everything that changed since the last call, moving the board to a new version.
+    /// Returns `None` if nothing happened.
+    pub fn take_changes(&mut self) -> Option<Changes> {
+        if self.changes.is_empty() {
+            return None;
+        }
+
+        self.version += 1;
+        Some(std::mem::take(&mut self.changes))
+    }
+
+    /// Turn a set of changes into the patch `who` should receive, leaving out what changed in
+    /// anyone else's private view
+    pub fn patch_for(&self, changes: &Changes, who: Option<&str>) -> DigSitePatch {
+        let private = who.and_then(|id| changes.private.get(id));
+        let cells = changes
+            .cells
+            .union(private.unwrap_or(&BTreeSet::new()))
+            .map(|&index| CellPatch {
+                index,
+                state: self.cell_state(index, who),
             })
+            .collect();
+
+        let players = changes
+            .players
+            .iter()
+            .filter_map(|id| Some((id.clone(), self.players.get(id)?.clone())))
+            .collect();
+
+        let removed = changes
+            .players
+            .iter()
+            .filter(|id| !self.players.contains_key(*id))
+            .cloned()
+            .collect();
+
+        DigSitePatch {
+            version: self.version,
+            cells,
+            players,
+            removed,
+            scoreboard: self.scoreboard(),
+            seed: self.public_seed(),
+            status: self.status.clone(),
+            paused: self.paused,
+            turn: self.turn.clone(),
+        }
+    }
+
+    /// What `who` can see of a single cell
+    fn cell_state(&self, index: usize, who: Option<&str>) -> CellState {
+        let point = Area::from(self.dimensions).point_from_pos(index);
+        match (self.is_hidden(point, who), self.board.get(index)) {
+            (Some(false), Some(cell)) => CellState::Visible(*cell),
+            (Some(true), _) => match self.marks_for(who).get(index).cloned().flatten() {
+                Some(mark) => CellState::Marked(mark),
+                None => CellState::Hidden,
+            },
+            _ => CellState::Hidden,
+        }
+    }
+
+    /// The shared view of the board. With per-player fog this only shows the starting area.
+    pub fn output(&self) -> DigSiteOutput {
+        self.output_for(None)
+    }
+
+    /// The board as seen by a specific player
+    pub fn output_for(&self, who: Option<&str>) -> DigSiteOutput {
+        let board = (0..self.board.len())
+            .map(|i| self.cell_state(i, who))
             .collect::<Vec<_>>()
             .chunks(self.dimensions.x)
             .map(Vec::from)
             .collect();
 
         DigSiteOutput {
+            bones: self.bones(),
             players: self.players.clone(),
+            scoreboard: self.scoreboard(),
+            seed: self.public_seed(),
+            daily: self.daily,
             board,
+            status: self.status.clone(),
+            paused: self.paused,
+            turn: self.turn.clone(),
+            clock: self.clock(now_millis()),
+            version: self.version,
         }
     }
 
@@ -462,3 +1377,197 @@ impl DigSite {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::config::Difficulty;
+
+    /// A game for player `p` standing on `spawn`, on a board drawn as rows where `b` is a hidden
+    /// bone, `.` a hidden safe cell and `o` a revealed safe cell
+    fn game(rows: &[&str], spawn: Point) -> DigSite {
+        game_with(rows, spawn, Rules::default(), &["p"])
+    }
+
+    /// Like [game] with other rules and players
+    fn game_with(rows: &[&str], spawn: Point, rules: Rules, players: &[&str]) -> DigSite {
+        let size = Size {
+            x: rows[0].len(),
+            y: rows.len(),
+        };
+        let chars: Vec<_> = rows.iter().flat_map(|r| r.chars()).collect();
+
+        let mut ds = DigSite::new(size);
+        ds.board = chars
+            .iter()
+            .map(|c| {
+                if *c == 'b' {
+                    Cell::Bone
+                } else {
+                    Cell::Empty(0)
+                }
+            })
+            .collect();
+        ds.apply_cell_state().unwrap();
+        ds.state = chars.iter().map(|c| *c == 'o').collect();
+        ds.spawn_pos = Some(spawn);
+        ds.rules = rules;
+        for id in players {
+            ds.add_player(id.to_string()).unwrap();
+        }
+        ds.take_changes();
+        ds
+    }
+
+    const LEFT: Point = Point { x: -1, y: 0 };
+    const RIGHT: Point = Point { x: 1, y: 0 };
+
+    #[test]
+    fn stepping_on_a_bone_loses() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+
+        ds.move_player(String::from("p"), LEFT).unwrap();
+        assert!(matches!(
+            ds.status(),
+            GameStatus::Lost { by, pos, .. } if by == "p" && *pos == Point { x: 0, y: 0 }
+        ));
+        assert!(ds.status().ended_at().is_some());
+
+        // Nothing can change the outcome anymore
+        assert!(ds.move_player(String::from("p"), RIGHT).is_err());
+    }
+
+    #[test]
+    fn clearing_the_board_wins() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+        assert_eq!(*ds.status(), GameStatus::InProgress);
+
+        ds.move_player(String::from("p"), RIGHT).unwrap();
+        assert!(matches!(ds.status(), GameStatus::Won { by, .. } if by == "p"));
+    }
+
+    #[test]
+    fn digging_a_hidden_bone_loses() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+
+        ds.excavate(String::from("p"), LEFT).unwrap();
+        assert!(matches!(
+            ds.status(),
+            GameStatus::Lost { pos, .. } if *pos == Point { x: 0, y: 0 }
+        ));
+    }
+
+    #[test]
+    fn digging_a_flagged_bone_collects_it() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+        let bone = Point { x: 0, y: 0 };
+
+        ds.set_mark(String::from("p"), bone, Some(MarkKind::Flag))
+            .unwrap();
+        ds.excavate(String::from("p"), LEFT).unwrap();
+        assert_eq!(*ds.status(), GameStatus::InProgress);
+        assert_eq!(ds.get(bone), Some(Cell::Excavated));
+        assert_eq!(ds.bones_found(), 1);
+    }
+
+    #[test]
+    fn digging_under_a_wrong_flag_loses() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+        let empty = Point { x: 2, y: 0 };
+
+        ds.set_mark(String::from("p"), empty, Some(MarkKind::Flag))
+            .unwrap();
+        ds.excavate(String::from("p"), RIGHT).unwrap();
+        assert!(matches!(ds.status(), GameStatus::Lost { pos, .. } if *pos == empty));
+    }
+
+    #[test]
+    fn chording_counts_excavated_bones() {
+        let mut ds = game(&["bo."], Point { x: 1, y: 0 });
+
+        ds.set_mark(
+            String::from("p"),
+            Point { x: 0, y: 0 },
+            Some(MarkKind::Flag),
+        )
+        .unwrap();
+        ds.excavate(String::from("p"), LEFT).unwrap();
+
+        // The 1 is satisfied by the bone that was dug up, no flag is left next to it
+        ds.chord(String::from("p"), Point { x: 0, y: 0 }).unwrap();
+        assert_eq!(ds.is_hidden(Point { x: 2, y: 0 }, None), Some(false));
+        assert!(matches!(ds.status(), GameStatus::Won { .. }));
+    }
+
+    #[test]
+    fn disconnected_players_keep_their_slot() {
+        let mut ds = game_with(
+            &["...", "...", "..."],
+            Point { x: 1, y: 1 },
+            Rules::default(),
+            &["a", "b"],
+        );
+        ds.disconnect_player("b");
+        assert_eq!(ds.player_count(), 2);
+
+        ds.remove_player("b");
+        assert_eq!(ds.player_count(), 1);
+    }
+
+    #[test]
+    fn private_views_only_patch_their_owner() {
+        let rules = Rules {
+            visibility: Visibility::PerPlayer,
+            ..Rules::default()
+        };
+        let mut ds = game_with(&["bo.."], Point { x: 1, y: 0 }, rules, &["a", "b"]);
+
+        ds.set_mark(
+            String::from("a"),
+            Point { x: 0, y: 0 },
+            Some(MarkKind::Flag),
+        )
+        .unwrap();
+        ds.move_player(String::from("a"), RIGHT).unwrap();
+        let changes = ds.take_changes().unwrap();
+
+        let cells = |who| -> Vec<_> {
+            ds.patch_for(&changes, Some(who))
+                .cells
+                .iter()
+                .map(|c| c.index)
+                .collect()
+        };
+        assert_eq!(cells("a"), [0, 2, 3]);
+        assert!(cells("b").is_empty());
+    }
+
+    #[test]
+    fn seeds_keep_their_board() {
+        // Shared seeds and daily boards rely on this never changing, not even with a new rand
+        let ds = DigSite::from_seed(Seed(0x5eed), &Difficulty::Beginner.config()).unwrap();
+        let bones: Vec<_> = (0..ds.board.len())
+            .filter(|i| ds.board[*i] == Cell::Bone)
+            .collect();
+        assert_eq!(bones, [1, 4, 7, 18, 44, 63, 68, 73, 76, 80]);
+    }
+
+    #[test]
+    fn seeded_no_guess_board_is_solvable() {
+        let config = Difficulty::Intermediate.config();
+        let ds = DigSite::from_seed(Seed(0x5eed), &config).unwrap();
+        let again = DigSite::from_seed(Seed(0x5eed), &config).unwrap();
+        assert_eq!(ds.board, again.board);
+        assert_eq!(ds.bones(), 40);
+
+        let mut solver = Solver::new(ds.dimensions, &ds.board, &ds.state);
+        assert!(solver.solve());
+
+        // Every deduction has to hold up against the real board
+        for (i, cell) in ds.board.iter().enumerate() {
+            let is_bone = matches!(cell, Cell::Bone);
+            assert_eq!(solver.revealed[i], !is_bone, "cell {} was misjudged", i);
+            assert!(!solver.flagged[i] || is_bone, "cell {} was flagged", i);
+        }
+    }
+}
@@ -1 +1,6 @@
+pub mod config;
+pub mod daily;
 pub mod digsites;
+pub mod seed;
+pub mod solver;
+pub mod turns;
@@ -0,0 +1,55 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Error, Result};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The seed a board is generated from. The same seed and [GameConfig](super::config::GameConfig)
+/// always produce the same board.
+///
+/// Seeds travel as hex strings since a u64 doesn't survive a round trip through a javascript
+/// number.
+pub struct Seed(pub u64);
+
+impl Seed {
+    pub fn random() -> Self {
+        Seed(rand::random())
+    }
+
+    /// The generator boards are rolled with. Unlike `StdRng` its output is fixed across releases,
+    /// so a seed keeps producing the same board after a dependency update.
+    pub fn rng(&self) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(self.0)
+    }
+}
+
+impl fmt::Display for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for Seed {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        u64::from_str_radix(s.trim(), 16)
+            .map(Seed)
+            .with_context(|| format!("invalid seed {:?}", s))
+    }
+}
+
+impl Serialize for Seed {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Seed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
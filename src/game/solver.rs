@@ -0,0 +1,278 @@
+use bitvec::vec::BitVec;
+
+use crate::geometry::{Area, Point, Size};
+
+use super::digsites::Cell;
+
+/// A deterministic minesweeper solver that only ever makes moves that are logically forced by
+/// what's currently visible. If it can clear the board then so can a player, without guessing.
+pub(crate) struct Solver<'a> {
+    dimensions: Size,
+    board: &'a [Cell],
+    pub(super) revealed: BitVec,
+    pub(super) flagged: BitVec,
+    bones: usize,
+}
+
+/// What a single revealed number tells us about its hidden neighbours
+struct Constraint {
+    at: Point,
+    unknown: Vec<usize>,
+    bones: usize,
+}
+
+impl<'a> Solver<'a> {
+    pub fn new(dimensions: Size, board: &'a [Cell], revealed: &BitVec) -> Self {
+        Solver {
+            dimensions,
+            board,
+            revealed: revealed.clone(),
+            flagged: BitVec::repeat(false, board.len()),
+            bones: board.iter().filter(|c| matches!(c, Cell::Bone)).count(),
+        }
+    }
+
+    /// Keep deducing until the board is clear or nothing more can be learned.
+    /// Returns true if every safe cell could be revealed.
+    pub fn solve(&mut self) -> bool {
+        while !self.is_solved() {
+            if !(self.apply_single_rules() || self.apply_subset_rules() || self.apply_global_rule())
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn is_solved(&self) -> bool {
+        self.board
+            .iter()
+            .zip(self.revealed.iter())
+            .all(|(cell, visible)| matches!(cell, Cell::Bone) || *visible)
+    }
+
+    /// A number with all its bones flagged is safe around it, and a number with exactly as many
+    /// hidden neighbours as missing bones has a bone in each of them.
+    fn apply_single_rules(&mut self) -> bool {
+        let mut progress = false;
+
+        for c in self.constraints() {
+            if c.bones == 0 {
+                c.unknown.iter().for_each(|i| self.reveal(*i));
+                progress = true;
+            } else if c.bones == c.unknown.len() {
+                c.unknown.iter().for_each(|i| self.flagged.set(*i, true));
+                progress = true;
+            }
+        }
+
+        progress
+    }
+
+    /// When the unknowns of one number are contained in another's, the leftover cells hold the
+    /// difference of their bones.
+    fn apply_subset_rules(&mut self) -> bool {
+        let constraints = self.constraints();
+        let mut progress = false;
+
+        for a in &constraints {
+            // Only numbers at most two cells apart can share hidden neighbours
+            let nearby = Area::around_point(a.at, 2);
+
+            for b in &constraints {
+                if a.unknown.len() >= b.unknown.len() || b.bones < a.bones || !nearby.contains(b.at)
+                {
+                    continue;
+                }
+
+                if !a.unknown.iter().all(|i| b.unknown.contains(i)) {
+                    continue;
+                }
+
+                let rest: Vec<_> = b
+                    .unknown
+                    .iter()
+                    .filter(|i| !a.unknown.contains(i))
+                    .copied()
+                    .collect();
+                let bones = b.bones - a.bones;
+
+                if bones == 0 {
+                    rest.iter().for_each(|i| self.reveal(*i));
+                    progress = true;
+                } else if bones == rest.len() {
+                    rest.iter().for_each(|i| self.flagged.set(*i, true));
+                    progress = true;
+                }
+            }
+        }
+
+        progress
+    }
+
+    /// Once every bone is accounted for the rest of the board is safe, and once the unknown cells
+    /// match the bones left they are all bones. Players are sent the board's bone total, so they
+    /// can make the same deduction.
+    fn apply_global_rule(&mut self) -> bool {
+        let unknown: Vec<_> = (0..self.board.len())
+            .filter(|i| !self.revealed[*i] && !self.flagged[*i])
+            .collect();
+        let bones = self.bones.saturating_sub(self.flagged.count_ones());
+
+        if unknown.is_empty() {
+            false
+        } else if bones == 0 {
+            unknown.iter().for_each(|i| self.reveal(*i));
+            true
+        } else if bones == unknown.len() {
+            unknown.iter().for_each(|i| self.flagged.set(*i, true));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Every revealed number that still borders unknown cells
+    fn constraints(&self) -> Vec<Constraint> {
+        (0..self.board.len())
+            .filter(|i| self.revealed[*i])
+            .filter_map(|i| {
+                // Revealing a 0 always reveals its neighbours too, so only numbers can still
+                // border unknown cells
+                let Cell::Empty(n @ 1..) = self.board[i] else {
+                    return None;
+                };
+
+                let neighbours = self.neighbours(i);
+                let flags = neighbours.iter().filter(|n| self.flagged[**n]).count();
+                let unknown: Vec<_> = neighbours
+                    .into_iter()
+                    .filter(|n| !self.revealed[*n] && !self.flagged[*n])
+                    .collect();
+
+                if unknown.is_empty() {
+                    return None;
+                }
+
+                Some(Constraint {
+                    at: Area::from(self.dimensions).point_from_pos(i),
+                    unknown,
+                    bones: (n as usize).saturating_sub(flags),
+                })
+            })
+            .collect()
+    }
+
+    /// Uncover a cell the same way the game does, spreading out from empty cells
+    fn reveal(&mut self, index: usize) {
+        if self.revealed[index] {
+            return;
+        }
+
+        debug_assert!(
+            !matches!(self.board[index], Cell::Bone),
+            "solver revealed a bone"
+        );
+
+        self.revealed.set(index, true);
+
+        if matches!(self.board[index], Cell::Empty(0)) {
+            for n in self.neighbours(index) {
+                self.reveal(n);
+            }
+        }
+    }
+
+    fn neighbours(&self, index: usize) -> Vec<usize> {
+        let dim_area = Area::from(self.dimensions);
+        let p = dim_area.point_from_pos(index);
+        let area = dim_area.intersecting_area(Area::around_point(p, 1));
+
+        let cell_count = Size::from(area).count();
+        let area_normalized = area.normalize();
+        let area_offset = area.0;
+
+        (0..cell_count)
+            .map(|pos| area_normalized.point_from_pos(pos) + area_offset)
+            .filter(|n| *n != p)
+            .map(|n| n.y as usize * self.dimensions.x + n.x as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a board from rows where `b` is a hidden bone, `.` a hidden safe cell and `o` a
+    /// revealed safe cell. Numbers are filled in from the bones.
+    fn board(rows: &[&str]) -> (Size, Vec<Cell>, BitVec) {
+        let size = Size {
+            x: rows[0].len(),
+            y: rows.len(),
+        };
+        let chars: Vec<_> = rows.iter().flat_map(|r| r.chars()).collect();
+        let area = Area::from(size);
+
+        let cells = (0..chars.len())
+            .map(|i| {
+                if chars[i] == 'b' {
+                    return Cell::Bone;
+                }
+                let around = area.intersecting_area(Area::around_point(area.point_from_pos(i), 1));
+                let bones = (0..chars.len())
+                    .filter(|j| chars[*j] == 'b' && around.contains(area.point_from_pos(*j)))
+                    .count();
+                Cell::Empty(bones as u8)
+            })
+            .collect();
+        let revealed = chars.iter().map(|c| *c == 'o').collect();
+
+        (size, cells, revealed)
+    }
+
+    #[test]
+    fn single_rules_flag_and_clear() {
+        let (size, cells, revealed) = board(&["obo."]);
+        let mut solver = Solver::new(size, &cells, &revealed);
+
+        assert!(solver.apply_single_rules());
+        assert!(solver.flagged[1]);
+        assert!(!solver.revealed[3]);
+
+        assert!(solver.apply_single_rules());
+        assert!(solver.revealed[3]);
+        assert!(solver.is_solved());
+    }
+
+    #[test]
+    fn subset_rule_clears_the_leftover() {
+        let (size, cells, revealed) = board(&["ooo", ".b."]);
+        let mut solver = Solver::new(size, &cells, &revealed);
+
+        assert!(!solver.apply_single_rules());
+        assert!(solver.apply_subset_rules());
+        assert!(solver.revealed[3] && solver.revealed[5]);
+        assert!(solver.is_solved());
+    }
+
+    #[test]
+    fn global_rule_uses_the_bone_count() {
+        let (size, cells, revealed) = board(&["ob.."]);
+        let mut solver = Solver::new(size, &cells, &revealed);
+
+        assert!(solver.apply_single_rules());
+        assert!(!solver.apply_subset_rules());
+        assert!(solver.apply_global_rule());
+        assert!(solver.revealed[2] && solver.revealed[3]);
+        assert!(solver.is_solved());
+    }
+
+    #[test]
+    fn coin_flips_are_not_solvable() {
+        let (size, cells, revealed) = board(&["oo", "b."]);
+
+        assert!(!Solver::new(size, &cells, &revealed).solve());
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::config::TurnRules;
+
+/// Someone tried to act while it was another player's turn
+#[derive(Debug)]
+pub struct NotYourTurn;
+
+impl fmt::Display for NotYourTurn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "it's not your turn")
+    }
+}
+
+impl std::error::Error for NotYourTurn {}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+/// Whose go it is in a turn based game
+pub struct Turn {
+    /// Everyone taking part, in the order they play
+    order: Vec<String>,
+    /// `None` while nobody that can play is around
+    player: Option<String>,
+    moves_left: usize,
+    /// Server time the turn is passed on if the player doesn't use up their moves, in milliseconds
+    /// since the unix epoch
+    ends_at: u64,
+    /// Counts every turn so clients can tell two turns of the same player apart
+    number: u64,
+}
+
+impl Turn {
+    pub fn player(&self) -> Option<&str> {
+        self.player.as_deref()
+    }
+
+    pub fn ends_at(&self) -> u64 {
+        self.ends_at
+    }
+
+    pub fn is_turn_of(&self, id: &str) -> bool {
+        self.player() == Some(id)
+    }
+
+    /// Queue a player up at the end of the order
+    pub fn join(&mut self, id: &str) {
+        if !self.order.iter().any(|p| p == id) {
+            self.order.push(id.to_string());
+        }
+    }
+
+    /// Take a player out of the order. Pass the turn on first if it's theirs.
+    pub fn leave(&mut self, id: &str) {
+        self.order.retain(|p| p != id);
+    }
+
+    /// Hand the turn to the next player in the order that `can_play`, coming back around to the
+    /// current player if nobody else can
+    pub fn advance<F>(&mut self, rules: &TurnRules, now: u64, can_play: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        let count = self.order.len();
+        let start = self
+            .player()
+            .and_then(|current| self.order.iter().position(|p| p == current))
+            .map_or(0, |i| i + 1);
+
+        self.player = (0..count)
+            .map(|i| &self.order[(start + i) % count])
+            .find(|p| can_play(p))
+            .cloned();
+        self.number += 1;
+        self.restart(rules, now);
+    }
+
+    /// Give the current player their full budget and time again
+    pub fn restart(&mut self, rules: &TurnRules, now: u64) {
+        self.moves_left = rules.moves;
+        self.ends_at = now + rules.timeout_secs * 1000;
+    }
+
+    /// Use up one move, returns true once the player has none left
+    pub fn spend(&mut self) -> bool {
+        self.moves_left = self.moves_left.saturating_sub(1);
+        self.moves_left == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: TurnRules = TurnRules {
+        moves: 2,
+        timeout_secs: 10,
+    };
+
+    fn turn(players: &[&str]) -> Turn {
+        let mut turn = Turn::default();
+        for id in players {
+            turn.join(id);
+        }
+        turn
+    }
+
+    #[test]
+    fn turns_go_around_in_order() {
+        let mut turn = turn(&["a", "b", "c"]);
+
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            turn.advance(&RULES, 0, |_| true);
+            seen.push(turn.player().unwrap().to_string());
+        }
+        assert_eq!(seen, ["a", "b", "c", "a"]);
+        assert_eq!(turn.number, 4);
+        assert_eq!(turn.ends_at(), 10_000);
+    }
+
+    #[test]
+    fn players_that_cant_play_are_skipped() {
+        let mut turn = turn(&["a", "b", "c"]);
+        turn.advance(&RULES, 0, |_| true);
+
+        turn.advance(&RULES, 0, |id| id != "b");
+        assert!(turn.is_turn_of("c"));
+
+        // Nobody else is around, so the turn comes back to the current player
+        turn.advance(&RULES, 0, |id| id == "c");
+        assert!(turn.is_turn_of("c"));
+
+        turn.advance(&RULES, 0, |_| false);
+        assert_eq!(turn.player(), None);
+    }
+
+    #[test]
+    fn turns_end_once_the_moves_are_spent() {
+        let mut turn = turn(&["a", "b"]);
+        turn.advance(&RULES, 0, |_| true);
+
+        assert!(!turn.spend());
+        assert!(turn.spend());
+
+        turn.leave("b");
+        turn.advance(&RULES, 5_000, |_| true);
+        assert!(turn.is_turn_of("a"));
+        assert!(!turn.spend());
+        assert_eq!(turn.ends_at(), 15_000);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod api;
+pub mod auth;
+pub mod config;
 pub mod game;
 pub mod geometry;
+pub mod persistence;
 pub mod websocket;
@@ -1,20 +1,27 @@
 use socketioxide::handler::ConnectHandler;
 
 use anyhow::{anyhow, Ok, Result};
-use axum::{
-    http::{HeaderMap, HeaderValue},
-    routing::get,
+use axum::routing::{get, post};
+use digsite::{
+    api::{token::exchange_token, ApiState},
+    auth::Auth,
+    config::ServerConfig,
+    game::daily::Leaderboard,
+    persistence::SnapshotStore,
+    websocket::{
+        lifecycle::on_connect,
+        state::{Connection, ConnectionQueryString, Parties},
+    },
 };
-use digsite::websocket::{
-    lifecycle::on_connect,
-    state::{Connection, ConnectionQueryString, DiscordUser, Parties},
+use reqwest::Client;
+use socketioxide::{
+    extract::{SocketRef, State},
+    SocketIo,
 };
-use reqwest::{header::AUTHORIZATION, Client};
-use socketioxide::{extract::SocketRef, SocketIo};
-use tracing::info;
+use tracing::{error, info};
 use tracing_subscriber::FmtSubscriber;
 
-async fn auth_socket_middleware(s: SocketRef) -> Result<()> {
+async fn auth_socket_middleware(s: SocketRef, auth: State<Auth>) -> Result<()> {
     let qs = s
         .req_parts()
         .uri
@@ -22,34 +29,40 @@ async fn auth_socket_middleware(s: SocketRef) -> Result<()> {
         .ok_or_else(|| anyhow!("uri contains invalid query string"))?;
 
     let cqs = serde_qs::from_str::<ConnectionQueryString>(qs)?;
+    let protocol = cqs.protocol()?;
 
-    let client = Client::new();
+    let user = auth.authenticate(cqs.token()).await?;
 
-    let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&cqs.bearer_token())?);
+    info!("Hello, {}! (protocol v{})", user.name(), protocol);
 
-    let user = client
-        .get("https://discord.com/api/users/@me")
-        .headers(headers)
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<DiscordUser>()
-        .await?;
-
-    info!("Hello, {}!", user.name());
-
-    s.extensions.insert(Connection::new(cqs, user));
+    s.extensions.insert(Connection::new(cqs, user, protocol));
 
     Ok(())
 }
 
+async fn shutdown_signal() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for shutdown: {}", err);
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(FmtSubscriber::default())?;
 
+    let config = ServerConfig::from_env()?;
+    let parties = Parties::new();
+    let snapshots = SnapshotStore::new(&config.snapshot_dir)?;
+    let client = Client::builder().timeout(config.http_timeout).build()?;
+    let auth = Auth::from_config(&config, client.clone())?;
+    let api = ApiState::new(&config, client);
+
     let (layer, io) = SocketIo::builder()
-        .with_state::<Parties>(Parties::new())
+        .with_state::<Parties>(parties.clone())
+        .with_state::<Leaderboard>(Leaderboard::new())
+        .with_state::<SnapshotStore>(snapshots.clone())
+        .with_state::<ServerConfig>(config.clone())
+        .with_state::<Auth>(auth)
         .build_layer();
 
     io.ns("/", on_connect.with(auth_socket_middleware));
@@ -57,17 +70,29 @@ async fn main() -> Result<()> {
 
     let app = axum::Router::new()
         .route("/", get(|| async { "Hello, World!" }))
+        .route("/api/token", post(exchange_token))
+        .with_state(api)
         .layer(layer);
 
-    let port = std::env::var("PORT").unwrap_or("3000".to_string());
+    tokio::spawn(
+        snapshots
+            .clone()
+            .run(parties.clone(), config.snapshot_interval),
+    );
 
-    let listener = tokio::net::TcpListener::bind(String::from("0.0.0.0:") + &port)
+    let listener = tokio::net::TcpListener::bind(String::from("0.0.0.0:") + &config.port)
         .await
         .unwrap();
 
-    info!("Starting server on port 0.0.0.0:{}", port);
+    info!("Starting server on port 0.0.0.0:{}", config.port);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    info!("Saving snapshots before shutting down");
+    snapshots.save_parties(&parties);
 
     Ok(())
 }
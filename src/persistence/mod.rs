@@ -0,0 +1,3 @@
+pub mod snapshots;
+
+pub use snapshots::SnapshotStore;
@@ -0,0 +1,302 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::MissedTickBehavior;
+use tracing::error;
+
+use crate::{
+    game::digsites::{now_millis, DigSite},
+    websocket::state::Parties,
+};
+
+/// Bump this whenever the serialized shape of [DigSite] changes and add a migration for the old
+/// version in [migrate].
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    saved_at: u64,
+    game: &'a DigSite,
+}
+
+/// The envelope is read first so the game can be migrated before it's parsed
+#[derive(Deserialize)]
+struct RawSnapshot {
+    version: u32,
+    saved_at: u64,
+    game: Value,
+}
+
+/// What a party's snapshot needs, worked out while its game is locked and done after
+enum Pending {
+    Delete,
+    Write {
+        data: Vec<u8>,
+        saved: (u64, u64),
+    },
+    /// The game didn't change, the snapshot is only marked as still current
+    Touch,
+}
+
+#[derive(Debug, Clone)]
+/// Keeps one json snapshot per party on disk, keyed by the party instance id, so games survive a
+/// server restart.
+pub struct SnapshotStore {
+    dir: PathBuf,
+    /// What was last written for every party, as the game's start time and version, so unchanged
+    /// games aren't written again
+    saved: Arc<DashMap<String, (u64, u64)>>,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("unable to create snapshot directory {}", dir.display()))?;
+        Ok(SnapshotStore {
+            dir,
+            saved: Arc::new(DashMap::new()),
+        })
+    }
+
+    pub fn save(&self, iid: &str, game: &DigSite) -> Result<()> {
+        let data = Self::encode(game)?;
+        self.write(iid, &data)?;
+        self.saved
+            .insert(iid.to_string(), (game.started_at(), game.version()));
+
+        Ok(())
+    }
+
+    fn encode(game: &DigSite) -> Result<Vec<u8>> {
+        let snapshot = SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            saved_at: now_millis(),
+            game,
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    fn write(&self, iid: &str, data: &[u8]) -> Result<()> {
+        // Write next to the real file and swap it in so a crash never leaves half a snapshot
+        let path = self.path(iid);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    /// Mark a snapshot as matching a game that is still being played
+    fn touch(&self, iid: &str) -> Result<()> {
+        fs::File::options()
+            .write(true)
+            .open(self.path(iid))?
+            .set_modified(SystemTime::now())?;
+
+        Ok(())
+    }
+
+    /// Forget a party's snapshot, it's fine if there never was one
+    pub fn delete(&self, iid: &str) -> Result<()> {
+        self.saved.remove(iid);
+
+        match fs::remove_file(self.path(iid)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the last snapshot written for the party is already this exact game
+    fn is_saved(&self, iid: &str, game: &DigSite) -> bool {
+        self.saved
+            .get(iid)
+            .is_some_and(|saved| *saved == (game.started_at(), game.version()))
+    }
+
+    /// Returns `None` when the party has never been saved. Nobody is connected to a restored game
+    /// yet and the time the server was down doesn't count as played.
+    pub fn load(&self, iid: &str) -> Result<Option<DigSite>> {
+        let path = self.path(iid);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let raw = serde_json::from_slice::<RawSnapshot>(&data)
+            .with_context(|| format!("corrupt snapshot for party {}", iid))?;
+        let mut game: DigSite = serde_json::from_value(migrate(raw.version, raw.game)?)?;
+
+        // Unchanged games are only touched, so the game was still running when the file was last
+        // modified
+        let touched_at = fs::metadata(&path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let down_since = touched_at.max(raw.saved_at);
+        game.disconnect_all();
+        game.skip_downtime(down_since, now_millis());
+
+        Ok(Some(game))
+    }
+
+    /// Instance ids come from clients, so every byte that isn't safe in a file name is escaped as
+    /// exactly two hex digits. Different ids always end up in different files.
+    fn path(&self, iid: &str) -> PathBuf {
+        let name: String = iid
+            .bytes()
+            .map(|b| {
+                if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+                    char::from(b).to_string()
+                } else {
+                    format!("%{:02x}", b)
+                }
+            })
+            .collect();
+        self.dir.join(name + ".json")
+    }
+
+    /// Write a snapshot of every party whose game changed since it was last saved. Finished games
+    /// have nothing left to restore, their snapshots are removed instead.
+    pub fn save_parties(&self, parties: &Parties) {
+        for party in parties.all() {
+            let iid = &party.id;
+            // Only serializing happens under the lock, players don't wait on the disk
+            let pending = party.lock_game().and_then(|game| match game.as_ref() {
+                Some(game) if game.status().is_over() => Ok(Some(Pending::Delete)),
+                Some(game) if !self.is_saved(iid, game) => Ok(Some(Pending::Write {
+                    data: Self::encode(game)?,
+                    saved: (game.started_at(), game.version()),
+                })),
+                Some(_) => Ok(Some(Pending::Touch)),
+                None => Ok(None),
+            });
+
+            let res = pending.and_then(|pending| match pending {
+                Some(Pending::Delete) => self.delete(iid),
+                Some(Pending::Write { data, saved }) => {
+                    self.write(iid, &data)?;
+                    self.saved.insert(iid.clone(), saved);
+                    Ok(())
+                }
+                Some(Pending::Touch) => self.touch(iid),
+                None => Ok(()),
+            });
+
+            if let Err(err) = res {
+                error!("Snapshot Error for party {}: {}", iid, err);
+            }
+        }
+    }
+
+    /// Periodically snapshot every party until the server shuts down
+    pub async fn run(self, parties: Parties, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let store = self.clone();
+            let parties = parties.clone();
+            let res = tokio::task::spawn_blocking(move || store.save_parties(&parties)).await;
+            if let Err(err) = res {
+                error!("Snapshot task failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Upgrade a serialized game from an older snapshot version to [SNAPSHOT_VERSION].
+/// Schema changes add an arm here that rewrites the json of the version before them.
+fn migrate(version: u32, game: Value) -> Result<Value> {
+    match version {
+        SNAPSHOT_VERSION => Ok(game),
+        v if v > SNAPSHOT_VERSION => {
+            bail!(
+                "snapshot version {} is newer than this server understands",
+                v
+            )
+        }
+        v => bail!("no migration from snapshot version {}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::game::{config::Difficulty, seed::Seed};
+
+    /// A store in its own directory under the system temp dir, so tests don't share snapshots
+    fn store(name: &str) -> SnapshotStore {
+        let dir = std::env::temp_dir().join(format!("digsite-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        SnapshotStore::new(dir).unwrap()
+    }
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn party_ids_are_escaped() {
+        let store = store("escaped");
+
+        assert_eq!(file_name(&store.path("party-1_a")), "party-1_a.json");
+        assert_eq!(
+            file_name(&store.path("we\u{100}ird.id")),
+            "we%c4%80ird%2eid.json"
+        );
+        assert_eq!(file_name(&store.path("../up")), "%2e%2e%2fup.json");
+        assert_ne!(store.path("a.b"), store.path("a%2eb"));
+        assert_eq!(store.path("../up").parent(), Some(store.dir.as_path()));
+    }
+
+    #[test]
+    fn restored_games_wait_for_their_players() {
+        let store = store("restored");
+        let mut game = DigSite::from_seed(Seed(1), &Difficulty::Beginner.config()).unwrap();
+        game.add_player("p".to_string()).unwrap();
+
+        // The game was started two minutes ago and the server went down one minute ago
+        let now = now_millis();
+        let mut json = serde_json::to_value(&game).unwrap();
+        json["started_at"] = (now - 120_000).into();
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION,
+            "saved_at": now - 60_000,
+            "game": json,
+        });
+        let path = store.path("party");
+        fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        let game = store.load("party").unwrap().unwrap();
+        assert!(game.has_player("p"));
+        let players = &serde_json::to_value(&game).unwrap()["players"];
+        assert_eq!(players["p"]["connected"], false);
+
+        let elapsed = game.elapsed(now_millis());
+        assert!((60_000..70_000).contains(&elapsed), "{}", elapsed);
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{game::digsites::now_millis, geometry::Point};
+
+/// Longest chat message in characters
+pub const MAX_CHAT_LENGTH: usize = 280;
+/// How many messages a party keeps for players that join later
+pub const CHAT_HISTORY_SIZE: usize = 50;
+/// How long clients should show a ping on the board
+pub const PING_DURATION: Duration = Duration::from_secs(5);
+
+const CHAT_LIMIT: usize = 5;
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+const PING_LIMIT: usize = 3;
+const PING_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ChatMessage {
+    pub from: String,
+    pub name: String,
+    pub text: String,
+    /// Server time in milliseconds since the unix epoch
+    pub at: u64,
+}
+
+impl ChatMessage {
+    pub fn new(from: String, name: String, text: String) -> Self {
+        ChatMessage {
+            from,
+            name,
+            text,
+            at: now_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+/// A short lived marker on the board to point something out to the party
+pub struct Ping {
+    pub from: String,
+    pub pos: Point,
+    /// How long to show the ping for, in milliseconds
+    pub duration: u64,
+}
+
+impl Ping {
+    pub fn new(from: String, pos: Point) -> Self {
+        Ping {
+            from,
+            pos,
+            duration: PING_DURATION.as_millis() as u64,
+        }
+    }
+}
+
+/// Allows every user `limit` events in any `window`
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    events: DashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            events: DashMap::new(),
+        }
+    }
+
+    /// Count an event for `uid`, returns false if they are over the limit
+    pub fn check(&self, uid: &str) -> bool {
+        let now = Instant::now();
+        let mut events = self.events.entry(uid.to_string()).or_default();
+        while events
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.window)
+        {
+            events.pop_front();
+        }
+
+        if events.len() >= self.limit {
+            return false;
+        }
+
+        events.push_back(now);
+        true
+    }
+}
+
+/// Everything a party needs for chatting
+#[derive(Debug)]
+pub struct Chat {
+    history: Mutex<VecDeque<ChatMessage>>,
+    pub messages: RateLimiter,
+    pub pings: RateLimiter,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat {
+            history: Mutex::new(VecDeque::new()),
+            messages: RateLimiter::new(CHAT_LIMIT, CHAT_WINDOW),
+            pings: RateLimiter::new(PING_LIMIT, PING_WINDOW),
+        }
+    }
+
+    pub fn push(&self, msg: ChatMessage) {
+        if let Ok(mut history) = self.history.lock() {
+            history.push_back(msg);
+            while history.len() > CHAT_HISTORY_SIZE {
+                history.pop_front();
+            }
+        };
+    }
+
+    /// Recent messages, oldest first
+    pub fn history(&self) -> Vec<ChatMessage> {
+        self.history
+            .lock()
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Chat {
+    fn default() -> Self {
+        Chat::new()
+    }
+}
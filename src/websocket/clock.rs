@@ -0,0 +1,70 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use socketioxide::extract::SocketRef;
+use tracing::{error, info};
+
+use crate::game::digsites::now_millis;
+
+use super::{
+    lifecycle::{broadcast_patch, emit_to_party},
+    protocol::ServerMessage,
+    state::{Parties, Party},
+};
+
+/// How often a party checks for anything that is due
+pub const TICK: Duration = Duration::from_secs(1);
+
+/// Everything in a party that happens on a timer rather than because of a player, like turns and
+/// time limits running out. Every tick of a running game sends the server's clock to the party so
+/// clients never have to keep time themselves. Runs until the party is deleted. `socket` only has
+/// to be in the party's room, it can disconnect in the meantime.
+pub async fn run(socket: SocketRef, parties: Parties, party: Arc<Party>) {
+    let mut interval = tokio::time::interval(TICK);
+
+    loop {
+        interval.tick().await;
+
+        let is_current = parties
+            .get(party.id.clone())
+            .is_some_and(|p| Arc::ptr_eq(&p, &party));
+        if !is_current {
+            info!("Party {} clock stopped", party.id);
+            return;
+        }
+
+        if let Err(err) = tick(&socket, &party) {
+            error!("Clock Error: {}", err);
+        }
+    }
+}
+
+fn tick(socket: &SocketRef, party: &Party) -> Result<()> {
+    let mut party_game = party.lock_game()?;
+    let Some(game) = party_game.as_mut() else {
+        return Ok(());
+    };
+
+    if game.status().is_over() || game.is_paused() {
+        return Ok(());
+    }
+
+    let now = now_millis();
+    let time_up = game.expire_time(now);
+    if time_up || game.expire_turn(now) {
+        broadcast_patch(socket, &party.id, game)?;
+    }
+
+    emit_to_party(socket, &party.id, ServerMessage::Clock(game.clock(now)))?;
+
+    if time_up {
+        info!("Party {} ran out of time", party.id);
+        emit_to_party(
+            socket,
+            &party.id,
+            ServerMessage::GameOver(game.status().clone()),
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{permissions::PartyAction, protocol::ActionKind};
+
+/// Why a client action was refused. Handlers return these through anyhow and they are picked back
+/// out with `downcast_ref` before replying, anything else is reported as [ActionError::Internal].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionError {
+    /// The message couldn't be parsed, the client is speaking a different protocol
+    InvalidMessage(String),
+    /// The game refused the action, e.g. walking into a wall or chording a hidden cell
+    InvalidAction(String),
+    InvalidConfig(String),
+    GameOver,
+    Paused,
+    NotYourTurn,
+    NotInParty,
+    NotInGame,
+    NoGame,
+    /// The party policy doesn't let this player do that
+    NotAllowed(PartyAction),
+    NotHost,
+    Banned,
+    Muted,
+    PartyFull,
+    RateLimited,
+    Internal,
+}
+
+impl ActionError {
+    /// Stable identifier clients can switch on, the message is only meant for humans
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidMessage(_) => "invalid_message",
+            Self::InvalidAction(_) => "invalid_action",
+            Self::InvalidConfig(_) => "invalid_config",
+            Self::GameOver => "game_over",
+            Self::Paused => "paused",
+            Self::NotYourTurn => "not_your_turn",
+            Self::NotInParty => "not_in_party",
+            Self::NotInGame => "not_in_game",
+            Self::NoGame => "no_game",
+            Self::NotAllowed(_) => "not_allowed",
+            Self::NotHost => "not_host",
+            Self::Banned => "banned",
+            Self::Muted => "muted",
+            Self::PartyFull => "party_full",
+            Self::RateLimited => "rate_limited",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// Protocol violations end the connection, everything else is just reported back
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::InvalidMessage(_))
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMessage(msg) => write!(f, "invalid message: {}", msg),
+            Self::InvalidAction(msg) => write!(f, "{}", msg),
+            Self::InvalidConfig(msg) => write!(f, "invalid game config: {}", msg),
+            Self::GameOver => write!(f, "game is already over"),
+            Self::Paused => write!(f, "game is paused"),
+            Self::NotYourTurn => write!(f, "it's not your turn"),
+            Self::NotInParty => write!(f, "not part of a party"),
+            Self::NotInGame => write!(f, "not a player in this game"),
+            Self::NoGame => write!(f, "no game has been started"),
+            Self::NotAllowed(action) => write!(f, "you are not allowed to {}", action),
+            Self::NotHost => write!(f, "only the host can do that"),
+            Self::Banned => write!(f, "you are banned from this party"),
+            Self::Muted => write!(f, "you are muted in this party"),
+            Self::PartyFull => write!(f, "every player slot is taken"),
+            Self::RateLimited => write!(f, "too many requests, slow down"),
+            Self::Internal => write!(f, "something went wrong on the server"),
+        }
+    }
+}
+
+impl std::error::Error for ActionError {}
+
+impl From<&anyhow::Error> for ActionError {
+    fn from(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<ActionError>()
+            .cloned()
+            .unwrap_or(ActionError::Internal)
+    }
+}
+
+/// Payload of the `error` event
+#[derive(Debug, Serialize, Clone)]
+pub struct ErrorReply {
+    pub code: &'static str,
+    pub message: String,
+    /// The action that failed, if the error was caused by one
+    pub action: Option<ActionKind>,
+}
+
+impl ErrorReply {
+    pub fn new(err: &ActionError, action: Option<ActionKind>) -> Self {
+        ErrorReply {
+            code: err.code(),
+            message: err.to_string(),
+            action,
+        }
+    }
+}
@@ -1,18 +1,37 @@
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
 use anyhow::{anyhow, bail, Ok, Result};
-use rand::{rngs, SeedableRng};
-use socketioxide::extract::{Data, SocketRef, State};
+use serde_json::Value;
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use tokio::task;
 use tracing::{error, info};
 
 use crate::{
-    game::digsites::DigSite,
-    geometry::{Point, Size},
+    config::ServerConfig,
+    game::{
+        config::GameConfig,
+        daily::{Daily, DailyEntry, Leaderboard},
+        digsites::DigSite,
+        seed::Seed,
+        turns::NotYourTurn,
+    },
+    geometry::Point,
+    persistence::SnapshotStore,
 };
 
-use super::state::{Connection, Parties};
+use super::{
+    chat::{ChatMessage, Ping, MAX_CHAT_LENGTH},
+    clock,
+    error::{ActionError, ErrorReply},
+    permissions::{PartyAction, PartyPolicy},
+    protocol::{
+        ActionAck, ActionKind, ChatHistory, ClientMessage, DailyLeaderboard, Handshake, KickNotice,
+        NewGameRequest, PartyOutput, ServerMessage, ACTION_EVENT,
+    },
+    state::{Connection, Parties, Party},
+};
 
-pub fn on_connect(socket: SocketRef, parties: State<Parties>) {
+pub fn on_connect(socket: SocketRef, parties: State<Parties>, snapshots: State<SnapshotStore>) {
     let Some(query) = socket.extensions.get::<Connection>() else {
         let res = socket.disconnect();
         if let Result::Err(err) = res {
@@ -21,110 +40,746 @@ pub fn on_connect(socket: SocketRef, parties: State<Parties>) {
         return;
     };
     let conn = query.clone();
+    drop(query);
 
     info!(
         "Socket.IO connected: {:?} {:?} {:?}",
         socket.ns(),
         socket.id,
-        query.room()
+        conn.room()
     );
 
     socket.on_disconnect(on_disconnect);
     socket.on(
-        "move",
-        |s: SocketRef, d: Data<String>, parties: State<Parties>| {
+        ACTION_EVENT,
+        |s: SocketRef,
+         d: Data<Value>,
+         ack: AckSender,
+         parties: State<Parties>,
+         leaderboard: State<Leaderboard>,
+         config: State<ServerConfig>| async move {
             let conn = s.extensions.get::<Connection>().unwrap().clone();
-            let res = move_player(s.clone(), conn, parties, d.0.clone());
-            if let Result::Err(err) = res {
-                error!("Move Error: {}", err);
-                // Attempt to disconnect the socket on failure
-                let _ = s.clone().disconnect();
+            let action = ClientMessage::action_of(&d.0);
+            let msg = match ClientMessage::parse(d.0) {
+                Result::Ok(msg) => msg,
+                Result::Err(err) => {
+                    reply_error(&s, ack, &err, action);
+                    return;
+                }
+            };
+
+            let name = msg.kind();
+            match handle_message(&s, &conn, &parties, &leaderboard, &config, msg).await {
+                Result::Ok(version) => {
+                    let res = ack.send(ActionAck::Ok { version });
+                    if let Result::Err(err) = res {
+                        error!("Ack Error: {}", err);
+                    }
+                }
+                Result::Err(err) => {
+                    reply_error(&s, ack, &ActionError::from(&err), Some(name));
+                    if !err.is::<ActionError>() {
+                        error!("Action Error ({}): {:?}", name, err);
+                    }
+                }
             };
         },
     );
-    socket.on("game", |s: SocketRef, parties: State<Parties>| {
-        let conn = s.extensions.get::<Connection>().unwrap().clone();
-        let res = new_game(s.clone(), conn, parties);
+
+    // Setting up the game can wait on the disk or the board generator, so it gets its own task
+    tokio::spawn(async move {
+        let res = init_user(socket.clone(), conn, &parties, &snapshots).await;
         if let Result::Err(err) = res {
-            error!("Move Error: {}", err);
+            error!("Socket Create Error: {}", err);
+            if let Some(err) = err.downcast_ref::<ActionError>() {
+                let _ = emit(&socket, ServerMessage::Error(ErrorReply::new(err, None)));
+            }
             // Attempt to disconnect the socket on failure
-            let _ = s.clone().disconnect();
+            let _ = socket.clone().disconnect();
         };
     });
+}
+
+/// Route a client message to whatever handles it
+async fn handle_message(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    leaderboard: &Leaderboard,
+    config: &ServerConfig,
+    msg: ClientMessage,
+) -> Result<Option<u64>> {
+    let version = match msg {
+        ClientMessage::Move { direction } => {
+            play(socket, conn, parties, leaderboard, |game, id| {
+                game.move_player(id, direction.offset())
+            })?
+        }
+        ClientMessage::Dig { direction } => {
+            play(socket, conn, parties, leaderboard, |game, id| {
+                game.excavate(id, direction.offset())
+            })?
+        }
+        ClientMessage::Chord { direction } => {
+            play(socket, conn, parties, leaderboard, |game, id| {
+                game.chord(id, direction.offset())
+            })?
+        }
+        ClientMessage::Mark { pos, kind } => {
+            play(socket, conn, parties, leaderboard, |game, id| {
+                game.set_mark(id, pos, kind)
+            })?
+        }
+        ClientMessage::NewGame(request) => {
+            new_game(socket, conn, parties, leaderboard, config, request).await?
+        }
+        ClientMessage::Pause { paused } => pause(socket, conn, parties, paused)?,
+        ClientMessage::SetPolicy { policy } => {
+            set_policy(socket, conn, parties, policy)?;
+            return Ok(None);
+        }
+        ClientMessage::TransferHost { to } => {
+            transfer_host(socket, conn, parties, to)?;
+            return Ok(None);
+        }
+        ClientMessage::SetModerator { user, moderator } => {
+            set_moderator(socket, conn, parties, user, moderator)?;
+            return Ok(None);
+        }
+        ClientMessage::Kick { user } => kick(socket, conn, parties, user, false)?,
+        ClientMessage::Ban { user } => kick(socket, conn, parties, user, true)?,
+        ClientMessage::Unban { user } => {
+            unban(socket, conn, parties, user)?;
+            return Ok(None);
+        }
+        ClientMessage::Mute { user, muted } => {
+            mute(socket, conn, parties, user, muted)?;
+            return Ok(None);
+        }
+        ClientMessage::Join => join(socket, conn, parties)?,
+        ClientMessage::Spectate => spectate(socket, conn, parties)?,
+        ClientMessage::Chat { text } => {
+            chat(socket, conn, parties, text)?;
+            return Ok(None);
+        }
+        ClientMessage::Ping { pos } => {
+            ping(socket, conn, parties, pos)?;
+            return Ok(None);
+        }
+        ClientMessage::Resync => resync(socket, conn, parties)?,
+        ClientMessage::Leaderboard => {
+            emit(
+                socket,
+                ServerMessage::Leaderboard(daily_leaderboard(leaderboard)),
+            )?;
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(version))
+}
+
+/// Apply an action to the party's game on behalf of the connected player and send everyone what
+/// changed
+fn play<F>(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    leaderboard: &Leaderboard,
+    action: F,
+) -> Result<u64>
+where
+    F: FnOnce(&mut DigSite, String) -> Result<()>,
+{
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    let mut party_game = party.lock_game()?;
+    let game = party_game.as_mut().ok_or(ActionError::NoGame)?;
+
+    if game.status().is_over() {
+        bail!(ActionError::GameOver);
+    }
+    if game.is_paused() {
+        bail!(ActionError::Paused);
+    }
+    if !game.has_player(&conn.user.id) {
+        bail!(ActionError::NotInGame);
+    }
 
-    let res = init_user(socket.clone(), conn, parties);
+    action(game, conn.user.id.clone()).map_err(|err| {
+        if err.is::<NotYourTurn>() {
+            ActionError::NotYourTurn
+        } else {
+            ActionError::InvalidAction(err.to_string())
+        }
+    })?;
+    record_daily(leaderboard, &instance, game);
+
+    broadcast_patch(socket, &instance, game)?;
+
+    // Actions are rejected once a game is over, so only the one that ended it gets here
+    if game.status().is_over() {
+        emit_to_party(
+            socket,
+            &instance,
+            ServerMessage::GameOver(game.status().clone()),
+        )?;
+    }
+
+    Ok(game.version())
+}
+
+/// Tell the client why its action failed, both in the ack and as an `error` event for clients that
+/// don't ask for acks. Protocol violations also drop the connection.
+fn reply_error(socket: &SocketRef, ack: AckSender, err: &ActionError, action: Option<ActionKind>) {
+    info!("Rejected {:?} from {:?}: {}", action, socket.id, err);
+
+    let reply = ErrorReply::new(err, action);
+    let res = ack.send(ActionAck::Error(reply.clone()));
+    if let Result::Err(err) = res {
+        error!("Ack Error: {}", err);
+    }
+
+    let res = emit(socket, ServerMessage::Error(reply));
     if let Result::Err(err) = res {
-        error!("Socket Create Error: {}", err);
-        // Attempt to disconnect the socket on failure
+        error!("Error Reply Error: {}", err);
+    }
+
+    if err.is_fatal() {
         let _ = socket.clone().disconnect();
+    }
+}
+
+fn emit(socket: &SocketRef, msg: ServerMessage) -> Result<()> {
+    socket.emit(msg.event(), msg)?;
+    Ok(())
+}
+
+pub(super) fn emit_to_party(socket: &SocketRef, instance: &str, msg: ServerMessage) -> Result<()> {
+    socket.within(instance.to_string()).emit(msg.event(), msg)?;
+    Ok(())
+}
+
+/// Send the board to everyone in the party. With per-player fog every socket gets its own view.
+pub(super) fn broadcast_game(socket: &SocketRef, instance: &str, game: &DigSite) -> Result<()> {
+    if !game.has_private_views() {
+        return emit_to_party(socket, instance, ServerMessage::Game(game.output()));
+    }
+
+    for s in socket.within(instance.to_string()).sockets()? {
+        let who = s.extensions.get::<Connection>().map(|c| c.user.id.clone());
+        emit(&s, ServerMessage::Game(game.output_for(who.as_deref())))?;
+    }
+
+    Ok(())
+}
+
+/// Send only what changed since the last broadcast to everyone in the party. Clients that can't
+/// apply patches get the full board instead.
+pub(super) fn broadcast_patch(
+    socket: &SocketRef,
+    instance: &str,
+    game: &mut DigSite,
+) -> Result<()> {
+    let Some(changes) = game.take_changes() else {
+        return Ok(());
     };
+
+    let sockets = socket.within(instance.to_string()).sockets()?;
+    let conns: Vec<_> = sockets
+        .iter()
+        .map(|s| s.extensions.get::<Connection>())
+        .collect();
+    let all_delta = conns
+        .iter()
+        .all(|c| c.as_ref().is_some_and(|c| c.features().delta_updates));
+
+    if !game.has_private_views() && all_delta {
+        return emit_to_party(
+            socket,
+            instance,
+            ServerMessage::Patch(game.patch_for(&changes, None)),
+        );
+    }
+
+    for (s, conn) in sockets.iter().zip(conns) {
+        let who = conn.as_ref().map(|c| c.user.id.as_str());
+        let msg = if conn.as_ref().is_some_and(|c| c.features().delta_updates) {
+            ServerMessage::Patch(game.patch_for(&changes, who))
+        } else {
+            ServerMessage::Game(game.output_for(who))
+        };
+        emit(s, msg)?;
+    }
+
+    Ok(())
 }
 
-fn move_player(
-    socket: SocketRef,
-    conn: Connection,
-    parties: State<Parties>,
-    data: String,
+/// Put a freshly won daily challenge on the leaderboard. Actions are rejected once a game is over
+/// so this only ever sees the winning action.
+fn record_daily(leaderboard: &Leaderboard, instance: &str, game: &DigSite) {
+    if let Some((daily, entry)) = DailyEntry::from_game(instance.to_string(), game) {
+        info!(
+            "Party {} finished daily {} in {}ms",
+            instance, daily, entry.time
+        );
+        leaderboard.record(daily, entry);
+    }
+}
+
+fn daily_leaderboard(leaderboard: &Leaderboard) -> DailyLeaderboard {
+    let daily = Daily::today();
+    DailyLeaderboard {
+        daily,
+        date: daily.to_string(),
+        entries: leaderboard.top(daily),
+    }
+}
+
+fn party_output(party: &Party) -> ServerMessage {
+    ServerMessage::Party(PartyOutput {
+        players: party.players.iter().map(|p| p.clone()).collect(),
+        spectators: party.spectators.iter().map(|p| p.clone()).collect(),
+        host: party.host(),
+        policy: party.policy(),
+        moderators: party.moderators.iter().map(|p| p.clone()).collect(),
+        banned: party.banned.iter().map(|p| p.clone()).collect(),
+        muted: party.muted.iter().map(|p| p.clone()).collect(),
+    })
+}
+
+/// Refuse the action unless the party policy lets this player do it
+fn require(party: &Party, conn: &Connection, action: PartyAction) -> Result<()> {
+    if !party.allows(&conn.user.id, action) {
+        bail!(ActionError::NotAllowed(action));
+    }
+
+    Ok(())
+}
+
+fn pause(socket: &SocketRef, conn: &Connection, parties: &Parties, paused: bool) -> Result<u64> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    require(&party, conn, PartyAction::Pause)?;
+
+    let mut party_game = party.lock_game()?;
+    let game = party_game.as_mut().ok_or(ActionError::NoGame)?;
+
+    if game.status().is_over() {
+        bail!(ActionError::GameOver);
+    }
+    game.set_paused(paused)
+        .map_err(|err| ActionError::InvalidAction(err.to_string()))?;
+
+    broadcast_patch(socket, &instance, game)?;
+
+    Ok(game.version())
+}
+
+fn set_policy(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    policy: PartyPolicy,
 ) -> Result<()> {
     let instance = conn.room();
     let party = parties
         .get(instance.clone())
-        .ok_or(anyhow!("party not initialized"))?;
-    let digsite = Arc::clone(&party.game);
-    let mut party_game = digsite
+        .ok_or(ActionError::NotInParty)?;
+    if !party.is_host(&conn.user.id) {
+        bail!(ActionError::NotHost);
+    }
+    policy
+        .validate()
+        .map_err(|err| ActionError::InvalidAction(err.to_string()))?;
+
+    *party
+        .policy
         .lock()
-        .map_err(|_| anyhow!("Failed to lock digsite"))?; // Handle lock error
-    let game = party_game.as_mut().ok_or(anyhow!("game not initialized"))?;
+        .map_err(|_| anyhow!("Failed to lock policy"))? = policy;
+
+    emit_to_party(socket, &instance, party_output(&party))
+}
+
+fn transfer_host(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    to: String,
+) -> Result<()> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if !party.is_host(&conn.user.id) {
+        bail!(ActionError::NotHost);
+    }
+    if !party.players.contains(&to) {
+        bail!(ActionError::InvalidAction(format!(
+            "{} is not in this party",
+            to
+        )));
+    }
+
+    info!("Party {} handed from {} to {}", party.id, conn.user.id, to);
+    party.moderators.remove(&to);
+    party.set_host(to);
+
+    emit_to_party(socket, &instance, party_output(&party))
+}
+
+fn set_moderator(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    user: String,
+    moderator: bool,
+) -> Result<()> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if !party.is_host(&conn.user.id) {
+        bail!(ActionError::NotHost);
+    }
+    if !party.can_moderate(&conn.user.id, &user) {
+        bail!(ActionError::InvalidAction(String::from(
+            "the host is always a moderator"
+        )));
+    }
+
+    if moderator {
+        party.moderators.insert(user);
+    } else {
+        party.moderators.remove(&user);
+    }
+
+    emit_to_party(socket, &instance, party_output(&party))
+}
+
+/// Throw someone out of the party and the game, optionally keeping them out for good. Returns the
+/// new game version.
+fn kick(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    user: String,
+    ban: bool,
+) -> Result<u64> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    require(&party, conn, PartyAction::Kick)?;
+    if !party.can_moderate(&conn.user.id, &user) {
+        bail!(ActionError::NotAllowed(PartyAction::Kick));
+    }
+
+    let version = {
+        let mut party_game = party.lock_game()?;
+
+        // Players that disconnected still hold their slot in the game, kicking them frees it
+        let in_game = party_game
+            .as_ref()
+            .is_some_and(|game| game.has_player(&user));
+        let in_party = party.players.remove(&user).is_some();
+        if !in_party && !in_game && !ban {
+            bail!(ActionError::InvalidAction(format!(
+                "{} is not in this party",
+                user
+            )));
+        }
+        party.moderators.remove(&user);
+        party.spectators.remove(&user);
+        party.forget(&user);
+        if ban {
+            party.banned.insert(user.clone());
+        }
 
-    let offset = match data.as_str() {
-        "up" => Point { x: 0, y: -1 },
-        "down" => Point { x: 0, y: 1 },
-        "left" => Point { x: -1, y: 0 },
-        "right" => Point { x: 1, y: 0 },
-        _ => bail!("invalid move"),
+        let game = party_game.as_mut().ok_or(ActionError::NoGame)?;
+        game.remove_player(&user);
+        broadcast_patch(socket, &instance, game)?;
+        game.version()
     };
 
-    game.move_player(conn.user.id.clone(), offset)?;
+    info!(
+        "Party {}: {} {} {}",
+        party.id,
+        conn.user.id,
+        if ban { "banned" } else { "kicked" },
+        user
+    );
+
+    // Their sockets have to go after the game lock is released, disconnecting runs the disconnect
+    // handler right away
+    for s in socket.within(instance.clone()).sockets()? {
+        let is_target = s
+            .extensions
+            .get::<Connection>()
+            .is_some_and(|c| c.user.id == user);
+        if is_target {
+            let notice = KickNotice {
+                by: conn.user.id.clone(),
+                banned: ban,
+            };
+            let _ = emit(&s, ServerMessage::Kicked(notice));
+            let _ = s.disconnect();
+        }
+    }
 
-    socket
-        .within(instance.clone())
-        .emit("game", game.output())?;
+    emit_to_party(socket, &instance, party_output(&party))?;
 
-    Ok(())
+    Ok(version)
 }
 
-fn new_game(socket: SocketRef, conn: Connection, parties: State<Parties>) -> Result<()> {
+fn unban(socket: &SocketRef, conn: &Connection, parties: &Parties, user: String) -> Result<()> {
     let instance = conn.room();
     let party = parties
         .get(instance.clone())
-        .ok_or(anyhow!("party not initialized"))?;
-    let digsite = Arc::clone(&party.game);
-    let mut party_game = digsite
-        .lock()
-        .map_err(|_| anyhow!("Failed to lock digsite"))?; // Handle lock error
+        .ok_or(ActionError::NotInParty)?;
+    require(&party, conn, PartyAction::Kick)?;
 
-    let mut rng = rngs::StdRng::from_entropy();
-    party_game.replace(DigSite::generate(
-        &mut rng,
-        Size { x: 10, y: 10 },
-        15,
-        Point { x: 5, y: 5 },
-    )?);
+    party.banned.remove(&user);
 
-    let game = party_game.as_mut().ok_or(anyhow!("game not initialized"))?;
-    party.players.iter().for_each(|p| {
-        game.add_player(p.clone()).unwrap();
-    });
+    emit_to_party(socket, &instance, party_output(&party))
+}
 
-    socket
-        .within(instance.clone())
-        .emit("game", game.output())?;
+fn mute(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    user: String,
+    muted: bool,
+) -> Result<()> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    require(&party, conn, PartyAction::Mute)?;
+    if !party.can_moderate(&conn.user.id, &user) {
+        bail!(ActionError::NotAllowed(PartyAction::Mute));
+    }
 
-    Ok(())
+    if muted {
+        party.muted.insert(user);
+    } else {
+        party.muted.remove(&user);
+    }
+
+    emit_to_party(socket, &instance, party_output(&party))
 }
 
-fn init_user(socket: SocketRef, conn: Connection, parties: State<Parties>) -> Result<()> {
+/// Move a spectator into the game if there's room
+fn join(socket: &SocketRef, conn: &Connection, parties: &Parties) -> Result<u64> {
     let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if !party.spectators.contains(&conn.user.id) {
+        bail!(ActionError::InvalidAction(String::from(
+            "you are already playing"
+        )));
+    }
+
+    let version = {
+        let mut party_game = party.lock_game()?;
+        let game = party_game.as_mut().ok_or(ActionError::NoGame)?;
+        if game.player_count() >= party.policy().max_players {
+            bail!(ActionError::PartyFull);
+        }
+
+        party.spectators.remove(&conn.user.id);
+        game.add_player(conn.user.id.clone())?;
+        broadcast_patch(socket, &instance, game)?;
+        game.version()
+    };
+    party.claim_host(&conn.user.id);
+
+    emit_to_party(socket, &instance, party_output(&party))?;
+
+    Ok(version)
+}
+
+/// Give up a player slot and watch instead
+fn spectate(socket: &SocketRef, conn: &Connection, parties: &Parties) -> Result<u64> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if !party.spectators.insert(conn.user.id.clone()) {
+        bail!(ActionError::InvalidAction(String::from(
+            "you are already spectating"
+        )));
+    }
+
+    let version = {
+        let mut party_game = party.lock_game()?;
+        let game = party_game.as_mut().ok_or(ActionError::NoGame)?;
+        game.remove_player(&conn.user.id);
+        broadcast_patch(socket, &instance, game)?;
+        game.version()
+    };
+
+    emit_to_party(socket, &instance, party_output(&party))?;
+
+    Ok(version)
+}
+
+fn chat(socket: &SocketRef, conn: &Connection, parties: &Parties, text: String) -> Result<()> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if party.muted.contains(&conn.user.id) {
+        bail!(ActionError::Muted);
+    }
+
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_CHAT_LENGTH {
+        bail!(ActionError::InvalidAction(format!(
+            "messages must be between 1 and {} characters",
+            MAX_CHAT_LENGTH
+        )));
+    }
+    if !party.chat.messages.check(&conn.user.id) {
+        bail!(ActionError::RateLimited);
+    }
+
+    let msg = ChatMessage::new(conn.user.id.clone(), conn.user.name(), text.to_string());
+    party.chat.push(msg.clone());
+
+    emit_to_party(socket, &instance, ServerMessage::Chat(msg))
+}
+
+fn ping(socket: &SocketRef, conn: &Connection, parties: &Parties, pos: Point) -> Result<()> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    if party.muted.contains(&conn.user.id) {
+        bail!(ActionError::Muted);
+    }
+
+    {
+        let party_game = party.lock_game()?;
+        let game = party_game.as_ref().ok_or(ActionError::NoGame)?;
+        if !game.in_bounds(pos) {
+            bail!(ActionError::InvalidAction(String::from(
+                "tried to ping a cell out of range"
+            )));
+        }
+    }
+
+    if !party.chat.pings.check(&conn.user.id) {
+        bail!(ActionError::RateLimited);
+    }
+
+    emit_to_party(
+        socket,
+        &instance,
+        ServerMessage::Ping(Ping::new(conn.user.id.clone(), pos)),
+    )
+}
+
+/// Send the full board to a client that noticed it missed a patch
+fn resync(socket: &SocketRef, conn: &Connection, parties: &Parties) -> Result<u64> {
+    let party = parties.get(conn.room()).ok_or(ActionError::NotInParty)?;
+    let party_game = party.lock_game()?;
+    let game = party_game.as_ref().ok_or(ActionError::NoGame)?;
+
+    emit(
+        socket,
+        ServerMessage::Game(game.output_for(Some(&conn.user.id))),
+    )?;
+
+    Ok(game.version())
+}
+
+async fn new_game(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    leaderboard: &Leaderboard,
+    config: &ServerConfig,
+    data: NewGameRequest,
+) -> Result<u64> {
+    let instance = conn.room();
+    let party = parties
+        .get(instance.clone())
+        .ok_or(ActionError::NotInParty)?;
+    require(&party, conn, PartyAction::NewGame)?;
+    if data.picks_board() {
+        require(&party, conn, PartyAction::Config)?;
+    }
+
+    // Boards that have to be solvable can take seconds to generate, so that happens on the
+    // blocking pool and the party only stays locked while the new board is swapped in
+    let generated = if data.daily {
+        let daily = Daily::today();
+        let players: Vec<_> = party
+            .players
+            .iter()
+            .filter(|p| !party.spectators.contains(p.key()))
+            .map(|p| p.clone())
+            .collect();
+        leaderboard
+            .start(daily, &players)
+            .map_err(|err| ActionError::InvalidAction(err.to_string()))?;
+
+        let secret = config.daily_secret;
+        task::spawn_blocking(move || DigSite::for_daily(daily, secret)).await??
+    } else {
+        let game_config = data.config();
+        game_config
+            .validate()
+            .map_err(|err| ActionError::InvalidConfig(err.to_string()))?;
+
+        let seed = data.seed.unwrap_or_else(Seed::random);
+        task::spawn_blocking(move || DigSite::from_seed(seed, &game_config))
+            .await?
+            .map_err(|err| ActionError::InvalidConfig(err.to_string()))?
+    };
+
+    let mut party_game = party.lock_game()?;
+
+    let game = party_game.insert(generated);
+    party
+        .players
+        .iter()
+        .filter(|p| !party.spectators.contains(p.key()))
+        .for_each(|p| {
+            game.add_player(p.clone()).unwrap();
+        });
+    game.take_changes();
+
+    broadcast_game(socket, &instance, game)?;
+
+    Ok(game.version())
+}
+
+async fn init_user(
+    socket: SocketRef,
+    conn: Connection,
+    parties: &Parties,
+    snapshots: &SnapshotStore,
+) -> Result<()> {
+    let instance = conn.room();
+
+    emit(
+        &socket,
+        ServerMessage::Handshake(Handshake::new(conn.protocol)),
+    )?;
+
+    if parties
+        .get(instance.clone())
+        .is_some_and(|party| party.banned.contains(&conn.user.id))
+    {
+        bail!(ActionError::Banned);
+    }
 
     socket.join(instance.clone())?;
     parties.ensure_party(instance.clone(), conn.user.id.clone());
@@ -133,64 +788,150 @@ fn init_user(socket: SocketRef, conn: Connection, parties: State<Parties>) -> Re
         .get(instance.clone())
         .ok_or(anyhow!("party not initialized"))?;
 
+    if !party.clock_started.swap(true, Ordering::AcqRel) {
+        tokio::spawn(clock::run(
+            socket.clone(),
+            Parties::clone(parties),
+            Arc::clone(&party),
+        ));
+    }
+
     info!("Party {} now {} large", party.id, party.players.len());
 
-    socket
-        .within(instance.clone())
-        .emit("party", vec![party.players.iter().collect::<Vec<_>>()])?;
+    emit(
+        &socket,
+        ServerMessage::ChatHistory(ChatHistory {
+            messages: party.chat.history(),
+        }),
+    )?;
+
+    // Restoring or generating a board blocks on the disk or the solver, so it happens on the
+    // blocking pool before the party is locked
+    let needs_game = party.lock_game()?.is_none();
+    let fresh = if needs_game {
+        let snapshots = snapshots.clone();
+        let instance = instance.clone();
+        Some(task::spawn_blocking(move || restore_or_generate(&snapshots, &instance)).await??)
+    } else {
+        None
+    };
 
-    let digsite = Arc::clone(&party.game);
-    let mut party_game = digsite
-        .lock()
-        .map_err(|_| anyhow!("Failed to lock digsite"))?; // Handle lock error
+    let mut party_game = party.lock_game()?;
 
-    if party_game.is_none() {
-        let mut rng = rngs::StdRng::from_entropy();
-        party_game.replace(DigSite::generate(
-            &mut rng,
-            Size { x: 10, y: 10 },
-            15,
-            Point { x: 5, y: 5 },
-        )?);
+    // The client may have left while the board was being prepared, and someone else may have
+    // prepared one first
+    if !socket.connected() {
+        return Ok(());
+    }
+    if let (None, Some(fresh)) = (party_game.as_ref(), fresh) {
+        party_game.replace(fresh);
     }
 
     let game = party_game.as_mut().ok_or(anyhow!("game not initialized"))?;
-    game.add_player(conn.user.id)?;
 
-    socket
-        .within(instance.clone())
-        .emit("game", game.output())?;
+    // Players coming back never gave up their slot, everyone else watches once the game is full
+    let uid = conn.user.id;
+    let is_full = !game.has_player(&uid) && game.player_count() >= party.policy().max_players;
+    if conn.spectate || is_full {
+        party.spectators.insert(uid.clone());
+        game.remove_player(&uid);
+    } else {
+        party.spectators.remove(&uid);
+        party.claim_host(&uid);
+        game.add_player(uid)?;
+    }
+    game.take_changes();
+
+    broadcast_game(&socket, &instance, game)?;
+    emit_to_party(&socket, &instance, party_output(&party))?;
 
     Ok(())
 }
 
-fn delete_user(socket: &SocketRef, conn: &Connection, parties: &Parties) -> Result<()> {
-    let instance = conn.room();
-    socket.leave(instance.clone())?;
-
-    let was_deleted = parties.on_player_left(instance.clone(), conn.user.id.clone());
-    if was_deleted {
-        let err = socket.to(instance.clone()).disconnect();
-        if err.is_err() {
-            bail!("failed to disconnect sockets");
+/// The party's game from its snapshot, or a fresh default board if it doesn't have one
+fn restore_or_generate(snapshots: &SnapshotStore, instance: &str) -> Result<DigSite> {
+    match snapshots.load(instance) {
+        Result::Ok(Some(game)) => {
+            info!("Party {} restored from snapshot", instance);
+            return Ok(game);
         }
-        return Ok(());
+        Result::Ok(None) => {}
+        Result::Err(err) => error!("Snapshot Restore Error: {}", err),
     }
 
+    DigSite::from_seed(Seed::random(), &GameConfig::default())
+}
+
+fn delete_user(
+    socket: &SocketRef,
+    conn: &Connection,
+    parties: &Parties,
+    config: &ServerConfig,
+    snapshots: &SnapshotStore,
+) -> Result<()> {
+    let instance = conn.room();
+    socket.leave(instance.clone())?;
+
     let party = parties
         .get(instance.clone())
         .ok_or(anyhow!("party not initialized"))?;
 
+    {
+        let mut party_game = party.lock_game()?;
+        if let Some(game) = party_game.as_mut() {
+            game.disconnect_player(&conn.user.id);
+            broadcast_patch(socket, &instance, game)?;
+        }
+    }
+
+    let is_empty = parties.on_player_left(instance.clone(), conn.user.id.clone());
+    if party.is_host(&conn.user.id) {
+        // The host keeps the role for as long as they could still reconnect
+        let socket = socket.clone();
+        let party = Arc::clone(&party);
+        let grace = config.reconnect_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if !party.replace_absent_host(grace) {
+                return;
+            }
+
+            info!("Party {} host is now {:?}", party.id, party.host());
+            if let Err(err) = emit_to_party(&socket, &party.id, party_output(&party)) {
+                error!("Host Handover Error: {}", err);
+            }
+        });
+    }
+    if is_empty {
+        info!(
+            "Party {} is empty, keeping it for {:?}",
+            party.id, config.reconnect_grace
+        );
+
+        let parties = parties.clone();
+        let snapshots = snapshots.clone();
+        let grace = config.reconnect_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            parties.remove_if_abandoned(&instance, grace, &snapshots);
+        });
+
+        return Ok(());
+    }
+
     info!("Party {} now {} large", party.id, party.players.len());
 
-    socket
-        .within(instance.clone())
-        .emit("party", vec![party.players.iter().collect::<Vec<_>>()])?;
+    emit_to_party(socket, &instance, party_output(&party))?;
 
     Ok(())
 }
 
-fn on_disconnect(socket: SocketRef, parties: State<Parties>) {
+fn on_disconnect(
+    socket: SocketRef,
+    parties: State<Parties>,
+    config: State<ServerConfig>,
+    snapshots: State<SnapshotStore>,
+) {
     if let Some(query) = socket.extensions.get::<Connection>() {
         info!(
             "Socket.IO disconnecting: {:?} {:?} {:?}",
@@ -199,7 +940,7 @@ fn on_disconnect(socket: SocketRef, parties: State<Parties>) {
             query.room()
         );
 
-        let res = delete_user(&socket, &query, &parties);
+        let res = delete_user(&socket, &query, &parties, &config, &snapshots);
         if let Result::Err(err) = res {
             error!("Socket Delete Error: {}", err);
         }
@@ -1,4 +1,9 @@
+pub mod chat;
+pub mod clock;
+pub mod error;
 pub mod lifecycle;
+pub mod permissions;
+pub mod protocol;
 pub mod state;
 
 pub use state::Connection;
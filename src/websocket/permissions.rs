@@ -0,0 +1,96 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// How many players can dig in a party unless the host changes it
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
+/// The most players a host can let into one game
+pub const MAX_PARTY_PLAYERS: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Who is allowed to do something in a party
+pub enum Permission {
+    Anyone,
+    /// The host and anyone they made a moderator
+    Moderator,
+    Host,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Party actions that are guarded by the [PartyPolicy]
+pub enum PartyAction {
+    /// Throw away the board and start over with the same kind of game
+    NewGame,
+    /// Pick the board for a new game, e.g. a difficulty, custom config, seed or the daily
+    Config,
+    /// Kick or ban someone from the party
+    Kick,
+    Mute,
+    Pause,
+}
+
+impl fmt::Display for PartyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewGame => write!(f, "start a new game"),
+            Self::Config => write!(f, "change the game config"),
+            Self::Kick => write!(f, "kick players"),
+            Self::Mute => write!(f, "mute players"),
+            Self::Pause => write!(f, "pause the game"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+/// How a party is run, what members may do without being the host and how many of them can play.
+/// Only the host can change it.
+pub struct PartyPolicy {
+    pub new_game: Permission,
+    pub config: Permission,
+    pub kick: Permission,
+    pub mute: Permission,
+    pub pause: Permission,
+    /// Everyone joining after the game is full becomes a spectator. Lowering it doesn't remove
+    /// anyone who is already playing.
+    pub max_players: usize,
+}
+
+impl PartyPolicy {
+    pub fn permission(&self, action: PartyAction) -> Permission {
+        match action {
+            PartyAction::NewGame => self.new_game,
+            PartyAction::Config => self.config,
+            PartyAction::Kick => self.kick,
+            PartyAction::Mute => self.mute,
+            PartyAction::Pause => self.pause,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=MAX_PARTY_PLAYERS).contains(&self.max_players) {
+            bail!(
+                "a party must allow between 1 and {} players, got {}",
+                MAX_PARTY_PLAYERS,
+                self.max_players
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PartyPolicy {
+    fn default() -> Self {
+        PartyPolicy {
+            new_game: Permission::Host,
+            config: Permission::Host,
+            kick: Permission::Moderator,
+            mute: Permission::Moderator,
+            pause: Permission::Host,
+            max_players: DEFAULT_MAX_PLAYERS,
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    game::{
+        config::{Difficulty, GameConfig},
+        daily::{Daily, DailyEntry},
+        digsites::{Clock, DigSiteOutput, DigSitePatch, GameStatus, MarkKind},
+        seed::Seed,
+    },
+    geometry::Point,
+};
+
+use super::{
+    chat::{ChatMessage, Ping},
+    error::{ActionError, ErrorReply},
+    permissions::PartyPolicy,
+};
+
+/// Name of the single event every client action is sent on
+pub const ACTION_EVENT: &str = "action";
+
+/// Newest protocol this server speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol still accepted. Version 1 clients sent `move` and `game` events the server no
+/// longer listens to.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Pick the protocol to talk to a client in, or refuse it if it's too old or doesn't say. Clients
+/// newer than this server are talked to in [PROTOCOL_VERSION], the handshake tells them so.
+pub fn negotiate(requested: Option<u32>) -> Result<u32> {
+    let Some(version) = requested else {
+        bail!(
+            "missing protocol version, this server speaks {} to {}",
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+    };
+    if version < MIN_PROTOCOL_VERSION {
+        bail!(
+            "unsupported protocol version {}, this server speaks {} to {}",
+            version,
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+    }
+
+    Ok(version.min(PROTOCOL_VERSION))
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+/// Optional parts of the protocol, depending on the version a client connected with
+pub struct Features {
+    /// `game:patch` updates after actions. Without this every change is sent as a full board.
+    pub delta_updates: bool,
+}
+
+impl Features {
+    /// Every feature is on from the version that introduced it
+    pub fn for_version(version: u32) -> Self {
+        Features {
+            delta_updates: version >= 2,
+        }
+    }
+}
+
+/// First thing sent to every client, so it knows what it can rely on
+#[derive(Debug, Serialize, Clone)]
+pub struct Handshake {
+    /// The version this connection will use
+    pub version: u32,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: Features,
+}
+
+impl Handshake {
+    pub fn new(version: u32) -> Self {
+        Handshake {
+            version,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: Features::for_version(version),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Where a player can walk to in one step
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub fn offset(&self) -> Point {
+        match self {
+            Self::Up => Point { x: 0, y: -1 },
+            Self::Down => Point { x: 0, y: 1 },
+            Self::Left => Point { x: -1, y: 0 },
+            Self::Right => Point { x: 1, y: 0 },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// A cell next to or under the player, for actions that don't move them
+pub enum Reach {
+    Here,
+    Up,
+    Down,
+    Left,
+    Right,
+    #[serde(alias = "up-left")]
+    UpLeft,
+    #[serde(alias = "up-right")]
+    UpRight,
+    #[serde(alias = "down-left")]
+    DownLeft,
+    #[serde(alias = "down-right")]
+    DownRight,
+}
+
+impl Reach {
+    pub fn offset(&self) -> Point {
+        let (x, y) = match self {
+            Self::Here => (0, 0),
+            Self::Up => (0, -1),
+            Self::Down => (0, 1),
+            Self::Left => (-1, 0),
+            Self::Right => (1, 0),
+            Self::UpLeft => (-1, -1),
+            Self::UpRight => (1, -1),
+            Self::DownLeft => (-1, 1),
+            Self::DownRight => (1, 1),
+        };
+        Point { x, y }
+    }
+}
+
+/// Either a preset or a full custom config. Sending neither uses the default board.
+/// A seed can be given to replay a board, otherwise a random one is picked.
+/// Asking for the daily challenge ignores everything else.
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct NewGameRequest {
+    pub difficulty: Option<Difficulty>,
+    pub config: Option<GameConfig>,
+    pub seed: Option<Seed>,
+    pub daily: bool,
+}
+
+impl NewGameRequest {
+    pub fn config(&self) -> GameConfig {
+        match (self.config.clone(), self.difficulty) {
+            (Some(config), _) => config,
+            (None, Some(difficulty)) => difficulty.config(),
+            (None, None) => GameConfig::default(),
+        }
+    }
+
+    /// Whether the request asks for a specific board rather than just a fresh default one
+    pub fn picks_board(&self) -> bool {
+        self.difficulty.is_some() || self.config.is_some() || self.seed.is_some() || self.daily
+    }
+}
+
+/// Everything a client can ask the server to do, sent on [ACTION_EVENT] as
+/// `{"type": "move", "direction": "up"}`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Move {
+        direction: Direction,
+    },
+    #[serde(alias = "excavate")]
+    Dig {
+        direction: Reach,
+    },
+    Chord {
+        direction: Reach,
+    },
+    /// Place a mark on `pos`, or clear it when no `kind` is given
+    Mark {
+        pos: Point,
+        #[serde(default)]
+        kind: Option<MarkKind>,
+    },
+    NewGame(NewGameRequest),
+    Pause {
+        paused: bool,
+    },
+    /// Host only, replace what other members are allowed to do
+    SetPolicy {
+        policy: PartyPolicy,
+    },
+    /// Host only, hand the host role to another member of the party
+    TransferHost {
+        to: String,
+    },
+    /// Host only, let someone else kick and mute
+    SetModerator {
+        user: String,
+        moderator: bool,
+    },
+    /// Remove someone from the party, they can join again right away
+    Kick {
+        user: String,
+    },
+    /// Remove someone from the party and keep them out
+    Ban {
+        user: String,
+    },
+    Unban {
+        user: String,
+    },
+    Mute {
+        user: String,
+        muted: bool,
+    },
+    /// Spectators take a free player slot
+    Join,
+    /// Players give up their slot and watch instead
+    Spectate,
+    Chat {
+        text: String,
+    },
+    /// Point out a cell to everyone in the party
+    Ping {
+        pos: Point,
+    },
+    /// Ask for the full board after noticing a missed patch
+    Resync,
+    Leaderboard,
+}
+
+impl ClientMessage {
+    /// The action a raw message is for, if it names one this server knows
+    pub fn action_of(value: &Value) -> Option<ActionKind> {
+        ActionKind::deserialize(value.get("type")?).ok()
+    }
+
+    /// Only a message that isn't an action at all is an [ActionError::InvalidMessage]. A known
+    /// action with a bad payload is an [ActionError::InvalidAction] the client can recover from.
+    pub fn parse(value: Value) -> Result<Self, ActionError> {
+        if Self::action_of(&value).is_none() {
+            return Err(ActionError::InvalidMessage(String::from(
+                "expected an object with a known `type`",
+            )));
+        }
+
+        serde_json::from_value(value).map_err(|err| ActionError::InvalidAction(err.to_string()))
+    }
+
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            Self::Move { .. } => ActionKind::Move,
+            Self::Dig { .. } => ActionKind::Dig,
+            Self::Chord { .. } => ActionKind::Chord,
+            Self::Mark { .. } => ActionKind::Mark,
+            Self::NewGame(_) => ActionKind::NewGame,
+            Self::Pause { .. } => ActionKind::Pause,
+            Self::SetPolicy { .. } => ActionKind::SetPolicy,
+            Self::TransferHost { .. } => ActionKind::TransferHost,
+            Self::SetModerator { .. } => ActionKind::SetModerator,
+            Self::Kick { .. } => ActionKind::Kick,
+            Self::Ban { .. } => ActionKind::Ban,
+            Self::Unban { .. } => ActionKind::Unban,
+            Self::Mute { .. } => ActionKind::Mute,
+            Self::Join => ActionKind::Join,
+            Self::Spectate => ActionKind::Spectate,
+            Self::Chat { .. } => ActionKind::Chat,
+            Self::Ping { .. } => ActionKind::Ping,
+            Self::Resync => ActionKind::Resync,
+            Self::Leaderboard => ActionKind::Leaderboard,
+        }
+    }
+}
+
+/// The `type` of every [ClientMessage], the one place the names clients send are spelled out
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    Move,
+    #[serde(alias = "excavate")]
+    Dig,
+    Chord,
+    Mark,
+    NewGame,
+    Pause,
+    SetPolicy,
+    TransferHost,
+    SetModerator,
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Join,
+    Spectate,
+    Chat,
+    Ping,
+    Resync,
+    Leaderboard,
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.serialize(f)
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PartyOutput {
+    pub players: Vec<String>,
+    pub spectators: Vec<String>,
+    pub host: Option<String>,
+    pub policy: PartyPolicy,
+    pub moderators: Vec<String>,
+    pub banned: Vec<String>,
+    pub muted: Vec<String>,
+}
+
+/// Recent chat, sent to everyone joining a party
+#[derive(Debug, Serialize, Clone)]
+pub struct ChatHistory {
+    pub messages: Vec<ChatMessage>,
+}
+
+/// Sent to someone right before they are removed from a party
+#[derive(Debug, Serialize, Clone)]
+pub struct KickNotice {
+    pub by: String,
+    pub banned: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DailyLeaderboard {
+    pub daily: Daily,
+    pub date: String,
+    pub entries: Vec<DailyEntry>,
+}
+
+/// Reply to every [ClientMessage] that was sent with an ack callback. `version` is the game version
+/// after the action was applied, so a client can tell which broadcast includes its own change.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ActionAck {
+    Ok { version: Option<u64> },
+    Error(ErrorReply),
+}
+
+/// Everything the server sends to clients. Each variant goes out on its own event, see
+/// [ServerMessage::event], with the inner value as the payload.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    Handshake(Handshake),
+    Game(DigSiteOutput),
+    Patch(DigSitePatch),
+    Party(PartyOutput),
+    Leaderboard(DailyLeaderboard),
+    GameOver(GameStatus),
+    Clock(Clock),
+    Kicked(KickNotice),
+    Chat(ChatMessage),
+    ChatHistory(ChatHistory),
+    Ping(Ping),
+    Error(ErrorReply),
+}
+
+impl ServerMessage {
+    pub fn event(&self) -> &'static str {
+        match self {
+            Self::Handshake(_) => "handshake",
+            Self::Game(_) => "game",
+            Self::Patch(_) => "game:patch",
+            Self::Party(_) => "party",
+            Self::Leaderboard(_) => "leaderboard",
+            Self::GameOver(_) => "game:over",
+            Self::Clock(_) => "clock",
+            Self::Kicked(_) => "kicked",
+            Self::Chat(_) => "chat",
+            Self::ChatHistory(_) => "chat:history",
+            Self::Ping(_) => "ping",
+            Self::Error(_) => "error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn clients_must_name_a_supported_version() {
+        assert!(negotiate(None).is_err());
+        assert!(negotiate(Some(1)).is_err());
+        assert_eq!(negotiate(Some(2)).unwrap(), 2);
+    }
+
+    #[test]
+    fn newer_clients_are_downgraded() {
+        assert_eq!(
+            negotiate(Some(PROTOCOL_VERSION + 5)).unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(
+            Handshake::new(PROTOCOL_VERSION).max_version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn messages_know_their_kind() {
+        for msg in [
+            json!({"type": "move", "direction": "up"}),
+            json!({"type": "dig", "direction": "here"}),
+            json!({"type": "mark", "pos": {"x": 1, "y": 2}}),
+            json!({"type": "new_game", "daily": true}),
+            json!({"type": "transfer_host", "to": "p"}),
+            json!({"type": "resync"}),
+        ] {
+            let kind = ClientMessage::action_of(&msg).unwrap();
+            assert_eq!(ClientMessage::parse(msg.clone()).unwrap().kind(), kind);
+            assert_eq!(kind.to_string(), msg["type"]);
+        }
+
+        let old = json!({"type": "excavate", "direction": "left"});
+        assert_eq!(ClientMessage::parse(old).unwrap().kind(), ActionKind::Dig);
+    }
+
+    #[test]
+    fn only_unknown_messages_are_invalid() {
+        let unknown = ClientMessage::parse(json!({"type": "teleport"}));
+        assert!(matches!(unknown, Err(ActionError::InvalidMessage(_))));
+
+        let bad_payload = ClientMessage::parse(json!({"type": "move", "direction": "sideways"}));
+        assert!(matches!(bad_payload, Err(ActionError::InvalidAction(_))));
+    }
+}
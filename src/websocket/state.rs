@@ -1,25 +1,48 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{atomic::AtomicBool, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
+use anyhow::{anyhow, Result};
 use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{error, info};
 
-use crate::game::digsites::DigSite;
+use crate::{game::digsites::DigSite, persistence::SnapshotStore};
+
+use super::{
+    chat::Chat,
+    permissions::{PartyAction, PartyPolicy, Permission},
+    protocol::{self, Features},
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Connection {
     iid: String,
     pub user: DiscordUser,
+    /// Protocol version agreed on when connecting
+    pub protocol: u32,
+    /// The client asked to watch instead of play
+    pub spectate: bool,
 }
 
 impl Connection {
-    pub fn new(qs: ConnectionQueryString, user: DiscordUser) -> Self {
-        Self { iid: qs.iid, user }
+    pub fn new(qs: ConnectionQueryString, user: DiscordUser, protocol: u32) -> Self {
+        Self {
+            iid: qs.iid,
+            user,
+            protocol,
+            spectate: qs.spec,
+        }
     }
 
     pub fn room(&self) -> String {
         self.iid.clone()
     }
+
+    pub fn features(&self) -> Features {
+        Features::for_version(self.protocol)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -32,7 +55,7 @@ pub struct DiscordUser {
 
 impl DiscordUser {
     pub fn name(&self) -> String {
-        return self.global_name.as_ref().unwrap_or(&self.username).clone();
+        self.global_name.as_ref().unwrap_or(&self.username).clone()
     }
 }
 
@@ -41,14 +64,26 @@ impl DiscordUser {
 pub struct ConnectionQueryString {
     iid: String,
     aut: String,
+    /// Protocol version the client was built for. Optional here so a client without one is
+    /// turned away by [protocol::negotiate] with a proper reason.
+    #[serde(default)]
+    ver: Option<u32>,
+    /// Join as a spectator
+    #[serde(default)]
+    spec: bool,
 }
 
 impl ConnectionQueryString {
-    pub fn bearer_token(&self) -> String {
-        String::from("Bearer ") + &self.aut.to_string()
+    pub fn protocol(&self) -> anyhow::Result<u32> {
+        protocol::negotiate(self.ver)
+    }
+
+    pub fn token(&self) -> &str {
+        &self.aut
     }
 }
 
+#[derive(Clone)]
 pub struct Parties(Arc<DashMap<String, Arc<Party>>>);
 
 impl Parties {
@@ -61,6 +96,12 @@ impl Parties {
         parties.get(&id).map(|r| Arc::clone(&r))
     }
 
+    /// Every party, for work that has to visit all of them
+    pub fn all(&self) -> Vec<Arc<Party>> {
+        let parties = Arc::clone(&self.0);
+        parties.iter().map(|p| Arc::clone(&p)).collect()
+    }
+
     pub fn add_party(&self, p: Party) {
         let parties = Arc::clone(&self.0);
         parties.insert(p.id.clone(), Arc::new(p));
@@ -69,31 +110,78 @@ impl Parties {
     pub fn ensure_party(&self, id: String, uid: String) {
         let parties = Arc::clone(&self.0);
         let party = parties.entry(id.clone());
-        party
-            .or_insert(Arc::new(Party::from(id)))
-            .players
-            .insert(uid);
+        let party = party.or_insert(Arc::new(Party::from(id)));
+        party.players.insert(uid.clone());
+        if let Ok(mut empty_since) = party.empty_since.lock() {
+            empty_since.take();
+        };
+        if party.is_host(&uid) {
+            if let Ok(mut host_left) = party.host_left.lock() {
+                host_left.take();
+            };
+        }
+        if let Ok(mut joined) = party.joined.lock() {
+            if !joined.contains(&uid) {
+                joined.push(uid);
+            }
+        };
     }
 
-    /// Returns true if the party was deleted bc of no players
+    /// Returns true if the party is now empty. The party isn't deleted right away so players
+    /// get a chance to reconnect, see [Parties::remove_if_abandoned]. A leaving host keeps the
+    /// role for now too, see [Party::replace_absent_host].
     pub fn on_player_left(&self, id: String, uid: String) -> bool {
-        let mut will_delete = false;
-
         let parties = Arc::clone(&self.0);
 
-        if let Some(party) = parties.get(&id) {
-            party.players.remove(&uid);
-            if party.players.is_empty() {
-                will_delete = true;
-            }
+        let Some(party) = parties.get(&id) else {
+            return false;
         };
 
-        if will_delete {
-            parties.remove(&id);
+        party.players.remove(&uid);
+        party.spectators.remove(&uid);
+        if party.is_host(&uid) {
+            if let Ok(mut host_left) = party.host_left.lock() {
+                host_left.replace(Instant::now());
+            };
+        }
+        if !party.players.is_empty() {
+            return false;
+        }
+
+        if let Ok(mut empty_since) = party.empty_since.lock() {
+            empty_since.get_or_insert_with(Instant::now);
+        };
+
+        true
+    }
+
+    /// Delete the party and its snapshot if it has been empty for at least `grace`.
+    /// Returns true if the party was deleted.
+    pub fn remove_if_abandoned(
+        &self,
+        id: &str,
+        grace: Duration,
+        snapshots: &SnapshotStore,
+    ) -> bool {
+        let parties = Arc::clone(&self.0);
+
+        let removed = parties.remove_if(id, |_, party| {
+            party.players.is_empty()
+                && party
+                    .empty_since
+                    .lock()
+                    .map(|since| since.is_some_and(|t| t.elapsed() >= grace))
+                    .unwrap_or(true)
+        });
+
+        if removed.is_some() {
             info!("Party {} deleted", id);
+            if let Err(err) = snapshots.delete(id) {
+                error!("Snapshot Delete Error for party {}: {}", id, err);
+            }
         }
 
-        will_delete
+        removed.is_some()
     }
 }
 
@@ -106,8 +194,120 @@ impl Default for Parties {
 #[derive(Debug)]
 pub struct Party {
     pub id: String,
+    /// Everyone in the party, including spectators
     pub players: DashSet<String>,
+    /// Members that watch the game without being part of it
+    pub spectators: DashSet<String>,
     pub game: Arc<Mutex<Option<DigSite>>>,
+    /// When the last player left, cleared again as soon as someone rejoins
+    pub empty_since: Mutex<Option<Instant>>,
+    /// The first player to join, handed over to someone else if they leave and don't come back
+    /// within the reconnect grace period
+    pub host: Mutex<Option<String>>,
+    /// When the host last left, cleared again when they come back
+    pub host_left: Mutex<Option<Instant>>,
+    /// Members in the order they first joined, the earliest player left becomes the next host
+    pub joined: Mutex<Vec<String>>,
+    pub policy: Mutex<PartyPolicy>,
+    pub moderators: DashSet<String>,
+    /// Users that can't rejoin the party, kept after they're gone
+    pub banned: DashSet<String>,
+    /// Users that can play but not chat or ping
+    pub muted: DashSet<String>,
+    pub chat: Chat,
+    /// Set once the party's [clock](super::clock::run) is running
+    pub clock_started: AtomicBool,
+}
+
+impl Party {
+    pub fn lock_game(&self) -> Result<MutexGuard<'_, Option<DigSite>>> {
+        self.game
+            .lock()
+            .map_err(|_| anyhow!("Failed to lock digsite"))
+    }
+
+    pub fn host(&self) -> Option<String> {
+        self.host.lock().ok().and_then(|host| host.clone())
+    }
+
+    pub fn is_host(&self, uid: &str) -> bool {
+        self.host().is_some_and(|host| host == uid)
+    }
+
+    pub fn set_host(&self, uid: String) {
+        if let Ok(mut host) = self.host.lock() {
+            host.replace(uid);
+        };
+    }
+
+    /// Make `uid` the host if nobody is
+    pub fn claim_host(&self, uid: &str) {
+        if let Ok(mut host) = self.host.lock() {
+            host.get_or_insert_with(|| uid.to_string());
+        };
+    }
+
+    /// Hand the host role to the earliest joined member that is still playing, once the host has
+    /// been gone for at least `grace`. Returns true if the host changed.
+    pub fn replace_absent_host(&self, grace: Duration) -> bool {
+        let (Ok(mut host), Ok(joined), Ok(mut host_left)) =
+            (self.host.lock(), self.joined.lock(), self.host_left.lock())
+        else {
+            return false;
+        };
+        if let Some(h) = host.as_ref() {
+            let gone_for_grace = host_left.is_some_and(|t| t.elapsed() >= grace);
+            if self.players.contains(h) || !gone_for_grace {
+                return false;
+            }
+        }
+        host_left.take();
+
+        let next = joined
+            .iter()
+            .find(|uid| self.players.contains(*uid) && !self.spectators.contains(*uid))
+            .cloned();
+        let changed = *host != next;
+        *host = next;
+
+        changed
+    }
+
+    /// Drop `uid` from the join order, they go to the back if they come back
+    pub fn forget(&self, uid: &str) {
+        if let Ok(mut joined) = self.joined.lock() {
+            joined.retain(|u| u != uid);
+        };
+    }
+
+    pub fn policy(&self) -> PartyPolicy {
+        self.policy
+            .lock()
+            .map(|policy| policy.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn is_moderator(&self, uid: &str) -> bool {
+        self.is_host(uid) || self.moderators.contains(uid)
+    }
+
+    /// Whether `uid` may do `action` under the party's current policy
+    pub fn allows(&self, uid: &str, action: PartyAction) -> bool {
+        match self.policy().permission(action) {
+            Permission::Anyone => self.players.contains(uid),
+            Permission::Moderator => self.is_moderator(uid),
+            Permission::Host => self.is_host(uid),
+        }
+    }
+
+    /// Nobody can moderate themselves or the host, and only the host can moderate moderators
+    pub fn can_moderate(&self, uid: &str, target: &str) -> bool {
+        if uid == target || self.is_host(target) {
+            return false;
+        }
+
+        !self.moderators.contains(target) || self.is_host(uid)
+    }
 }
 
 impl From<String> for Party {
@@ -115,7 +315,44 @@ impl From<String> for Party {
         Party {
             id: value,
             players: DashSet::new(),
+            spectators: DashSet::new(),
             game: Arc::new(Mutex::new(None)),
+            empty_since: Mutex::new(None),
+            host: Mutex::new(None),
+            host_left: Mutex::new(None),
+            joined: Mutex::new(Vec::new()),
+            policy: Mutex::new(PartyPolicy::default()),
+            moderators: DashSet::new(),
+            banned: DashSet::new(),
+            muted: DashSet::new(),
+            chat: Chat::new(),
+            clock_started: AtomicBool::new(false),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_is_replaced_only_after_being_gone_for_the_grace() {
+        let parties = Parties::new();
+        let id = String::from("party");
+        parties.ensure_party(id.clone(), String::from("host"));
+        parties.ensure_party(id.clone(), String::from("p"));
+        let party = parties.get(id.clone()).unwrap();
+        party.claim_host("host");
+
+        // A timer from the first leave must not hand over the role after the second one
+        parties.on_player_left(id.clone(), String::from("host"));
+        parties.ensure_party(id.clone(), String::from("host"));
+        assert!(!party.replace_absent_host(Duration::ZERO));
+        parties.on_player_left(id.clone(), String::from("host"));
+        assert!(!party.replace_absent_host(Duration::from_secs(60)));
+        assert!(party.is_host("host"));
+
+        assert!(party.replace_absent_host(Duration::ZERO));
+        assert!(party.is_host("p"));
+    }
+}